@@ -0,0 +1,218 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::process::{ExitStatus, ChildStdin, ChildStdout, ChildStderr};
+use std::sync::Arc;
+use std::task::{Context, Poll, Wake, Waker};
+#[cfg(any(target_os = "macos", target_os = "linux"))]
+use std::time::Duration;
+use kiruna::Priority;
+use crate::waitpid::{ProcessFuture, Pid, exit_status};
+use crate::Error;
+use crate::pipe;
+use crate::stream::OutputStream;
+
+///A waker for polls where nobody needs to hear about progress.
+struct NoopWaker;
+impl Wake for NoopWaker {
+    fn wake(self: Arc<Self>) {}
+}
+
+///How [Child::terminate_gracefully] ended the process.
+#[cfg(any(target_os = "macos", target_os = "linux"))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Shutdown {
+    ///The process had already exited before it was asked to stop.
+    AlreadyExited(ExitStatus),
+    ///The process exited within the grace period after SIGTERM.
+    Terminated(ExitStatus),
+    ///The process was still running after the grace period, so it was sent SIGKILL.
+    Killed(ExitStatus),
+}
+#[cfg(any(target_os = "macos", target_os = "linux"))]
+impl Shutdown {
+    ///The final status of the process, regardless of how it ended.
+    pub fn status(&self) -> ExitStatus {
+        match self {
+            Shutdown::AlreadyExited(status) | Shutdown::Terminated(status) | Shutdown::Killed(status) => *status
+        }
+    }
+}
+
+///A running process; compare with [std::process::Child].
+///
+/// Create one with [crate::Command::spawn].  Unlike [std::process::Child], the process is reaped by command-rs,
+/// so waiting is asynchronous.
+#[derive(Debug)]
+pub struct Child {
+    child: std::process::Child,
+    future: ProcessFuture,
+    ///Cached once the process is known to have exited, since it can only be reaped once.
+    status: Option<ExitStatus>,
+    ///Writes input from the [crate::Command], if there was any
+    input: Option<pipe::StdinTask>,
+    ///The child's stdin, if it was configured as [std::process::Stdio::piped]
+    pub stdin: Option<ChildStdin>,
+    ///The child's stdout, if it was configured as [std::process::Stdio::piped]
+    pub stdout: Option<ChildStdout>,
+    ///The child's stderr, if it was configured as [std::process::Stdio::piped]
+    pub stderr: Option<ChildStderr>,
+}
+
+impl Child {
+    pub(crate) fn new(mut child: std::process::Child) -> Child {
+        let future = ProcessFuture::new(child.id() as Pid);
+        Child {
+            stdin: child.stdin.take(),
+            stdout: child.stdout.take(),
+            stderr: child.stderr.take(),
+            child,
+            future,
+            status: None,
+            input: None,
+        }
+    }
+    ///Writes the inputs to stdin in the background, one after the other, then closes stdin.
+    pub(crate) fn write_stdin(&mut self, inputs: Vec<pipe::Input>, priority: Priority) {
+        let stdin = self.stdin.take().expect("stdin must be piped");
+        self.input = Some(pipe::StdinTask::new(stdin, inputs, priority));
+    }
+    ///Input from the [crate::Command], for whatever reads our output to write alongside
+    pub(crate) fn input(&self) -> Option<pipe::StdinTask> {
+        self.input.clone()
+    }
+    ///Stops writing input from the [crate::Command], reporting any error along the way.
+    ///
+    /// Call this once the process has exited.  Input it didn't read is discarded, as if it had closed stdin.
+    pub(crate) fn stdin_result(&mut self) -> Result<(), Error> {
+        match self.input.take() {
+            Some(input) => input.finish(),
+            None => Ok(())
+        }
+    }
+    ///The OS-assigned process identifier
+    pub fn id(&self) -> u32 {
+        self.child.id()
+    }
+    ///Waits for the process to exit.
+    ///
+    /// Like [std::process::Child::wait], stdin is closed first so that the process isn't stuck waiting on input.
+    /// If the [crate::Command] provided input, it's written while we wait, and errors writing it are reported here.  Once
+    /// the process exits, we stop writing, even if a [crate::StdinWriter] is still around.
+    pub async fn wait(&mut self, _priority: Priority) -> Result<ExitStatus, Error> {
+        drop(self.stdin.take());
+        let status = self.exited().await;
+        self.stdin_result()?;
+        Ok(status)
+    }
+    ///Reads stdout and stderr as the output arrives.
+    ///
+    /// This takes whichever of [Child::stdout] and [Child::stderr] are present, so configure them as [std::process::Stdio::piped]
+    /// on the [crate::Command].  Input from the [crate::Command] is written while the stream is read.
+    pub fn output_stream(&mut self, priority: Priority) -> OutputStream {
+        OutputStream::new(self.stdout.take(), self.stderr.take(), self.input(), priority)
+    }
+    ///Checks whether the process has exited, without waiting.
+    pub fn try_wait(&mut self) -> Result<Option<ExitStatus>, Error> {
+        if let Some(status) = self.status {
+            return Ok(Some(status))
+        }
+        let waker = Waker::from(Arc::new(NoopWaker));
+        let mut context = Context::from_waker(&waker);
+        match Pin::new(&mut self.future).poll(&mut context) {
+            Poll::Ready(raw) => {
+                let status = exit_status(raw);
+                self.status = Some(status);
+                Ok(Some(status))
+            }
+            Poll::Pending => Ok(None)
+        }
+    }
+    ///Forces the process to exit; compare with [std::process::Child::kill].
+    ///
+    /// This does nothing if the process is already known to have exited.  You still need to [Child::wait] afterwards
+    /// to learn the final status.
+    pub fn kill(&mut self) -> Result<(), Error> {
+        if self.status.is_some() {
+            return Ok(())
+        }
+        //Once a process is reaped, its pid may belong to somebody else, so the signal goes through the waitpid module,
+        //which knows whether that has happened.
+        #[cfg(any(target_os = "macos", target_os = "linux"))]
+        return Ok(self.future.signal(libc::SIGKILL)?);
+        #[cfg(target_os = "windows")]
+        {
+            //our process handle keeps the process from being reused
+            if self.try_wait()?.is_some() {
+                return Ok(())
+            }
+            Ok(self.child.kill()?)
+        }
+    }
+    ///Asks the process to exit with SIGTERM, and if it's still running after `grace`, kills it with SIGKILL.
+    ///
+    /// The result reports which of these steps ended the process.
+    #[cfg(any(target_os = "macos", target_os = "linux"))]
+    pub async fn terminate_gracefully(&mut self, grace: Duration) -> Result<Shutdown, Error> {
+        if let Some(status) = self.try_wait()? {
+            return Ok(Shutdown::AlreadyExited(status))
+        }
+        self.future.signal(libc::SIGTERM)?;
+        if let Some(status) = crate::timer::timeout(Box::pin(self.exited()), grace).await {
+            return Ok(Shutdown::Terminated(status))
+        }
+        self.kill()?;
+        Ok(Shutdown::Killed(self.exited().await))
+    }
+    ///Resolves when the process exits, writing input from the [crate::Command] in the meantime.
+    pub(crate) async fn exited(&mut self) -> ExitStatus {
+        if let Some(status) = self.status {
+            return status
+        }
+        let status = exit_status(pipe::alongside(self.input.as_ref(), &mut self.future).await);
+        self.status = Some(status);
+        status
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::Command;
+    use kiruna::Priority;
+    use std::time::Duration;
+
+    #[cfg(any(target_os = "macos", target_os = "linux"))]
+    #[test] fn kill() {
+        let _guard = crate::waitpid::test::TEST_SEMAPHORE.lock();
+        let mut child = Command::new("sleep").arg("10").spawn(Priority::Testing).unwrap();
+        assert!(child.id() > 0);
+        assert!(child.try_wait().unwrap().is_none());
+        child.kill().unwrap();
+        let status = kiruna::test::test_await(child.wait(Priority::Testing), Duration::from_secs(1)).unwrap();
+        use std::os::unix::process::ExitStatusExt;
+        assert_eq!(status.signal(), Some(libc::SIGKILL));
+        //stays available after the process was reaped
+        assert_eq!(child.try_wait().unwrap(), Some(status));
+        child.kill().unwrap();
+    }
+
+    #[cfg(any(target_os = "macos", target_os = "linux"))]
+    #[test] fn terminate_gracefully() {
+        use crate::child::Shutdown;
+        use std::os::unix::process::ExitStatusExt;
+        let _guard = crate::waitpid::test::TEST_SEMAPHORE.lock();
+        let mut polite = Command::new("sleep").arg("10").spawn(Priority::Testing).unwrap();
+        let shutdown = kiruna::test::test_await(polite.terminate_gracefully(Duration::from_secs(1)), Duration::from_secs(2)).unwrap();
+        assert_eq!(shutdown, Shutdown::Terminated(shutdown.status()));
+        assert_eq!(shutdown.status().signal(), Some(libc::SIGTERM));
+
+        //ignored signals stay ignored across exec
+        let mut stubborn = Command::new("sh").args(["-c", "trap '' TERM; exec sleep 10"]).spawn(Priority::Testing).unwrap();
+        std::thread::sleep(Duration::from_millis(100));
+        let shutdown = kiruna::test::test_await(stubborn.terminate_gracefully(Duration::from_millis(200)), Duration::from_secs(2)).unwrap();
+        assert_eq!(shutdown, Shutdown::Killed(shutdown.status()));
+        assert_eq!(shutdown.status().signal(), Some(libc::SIGKILL));
+
+        let shutdown = kiruna::test::test_await(stubborn.terminate_gracefully(Duration::from_millis(200)), Duration::from_secs(2)).unwrap();
+        assert!(matches!(shutdown, Shutdown::AlreadyExited(_)));
+    }
+}
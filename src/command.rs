@@ -1,53 +1,526 @@
-use std::ffi::OsStr;
-use std::process::{ExitStatus};
+use std::ffi::{OsStr, OsString};
+use std::process::{ExitStatus, Stdio};
+use std::time::Duration;
+use std::path::{Path, PathBuf};
 
 #[cfg(feature="output")]
+use crate::output::{Output, CombinedOutput, Capture, OutputLimit};
+use crate::{Error, ExitStatus as _};
+use crate::child::Child;
+use crate::pipe::{Input, StdinWriter};
+#[cfg(any(target_os = "macos", target_os = "linux"))]
+use crate::priority::ChildPriority;
+use kiruna::Priority;
+#[cfg(all(feature="output", not(target_os = "linux")))]
 use kiruna::io::stream::read::OSOptions;
-#[cfg(feature="output")]
-use crate::output::{Output};
-use crate::Error;
 
+///How much of the end of stderr a [CommandError] keeps
+const STDERR_TAIL: usize = 4 * 1024;
+
+///A failure of a particular command, with enough context to act on it without running it again.
+///
+/// This is [Error::Command], produced when a process can't launch, or by [Command::check] when it fails.
+#[derive(Debug)]
+pub struct CommandError {
+    pub program: OsString,
+    pub args: Vec<OsString>,
+    ///The working directory, if it was set with [Command::current_dir]
+    pub current_dir: Option<PathBuf>,
+    ///How the process ended, if it launched
+    pub status: Option<ExitStatus>,
+    ///The last few KB of stderr, if it was captured
+    pub stderr_tail: Vec<u8>,
+    ///What went wrong
+    pub error: Error,
+}
+impl std::fmt::Display for CommandError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "`{}", self.program.to_string_lossy())?;
+        for arg in &self.args {
+            let arg = arg.to_string_lossy();
+            if arg.is_empty() || arg.contains(char::is_whitespace) {
+                write!(f, " {:?}", arg)?;
+            }
+            else {
+                write!(f, " {}", arg)?;
+            }
+        }
+        write!(f, "`")?;
+        if let Some(dir) = &self.current_dir {
+            write!(f, " (in {})", dir.display())?;
+        }
+        match &self.status {
+            Some(status) => write!(f, " {}", status.termination())?,
+            None => write!(f, " failed: {}", self.error)?,
+        }
+        let stderr = String::from_utf8_lossy(&self.stderr_tail);
+        let stderr = stderr.trim_end();
+        if !stderr.is_empty() {
+            write!(f, "\nstderr:\n{}", stderr)?;
+        }
+        Ok(())
+    }
+}
+impl std::error::Error for CommandError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
 
 ///A process builder; compare with [std::process::Command]
-pub struct Command(std::process::Command);
+pub struct Command {
+    command: std::process::Command,
+    timeout: Option<Duration>,
+    current_dir: Option<PathBuf>,
+    validate_current_dir: bool,
+    input: Option<Input>,
+    #[cfg(feature="output")]
+    capture: Capture,
+    #[cfg(any(target_os = "macos", target_os = "linux"))]
+    priority: ChildPriority,
+}
 
 impl Command {
     pub fn new<S: AsRef<OsStr>>(program: S) -> Self {
-        Command(std::process::Command::new(program))
+        Command {
+            command: std::process::Command::new(program),
+            #[cfg(any(target_os = "macos", target_os = "linux"))]
+            priority: ChildPriority::default(),
+            timeout: None,
+            current_dir: None,
+            validate_current_dir: false,
+            input: None,
+            #[cfg(feature="output")]
+            capture: Capture::default(),
+        }
     }
     pub fn arg<S: AsRef<OsStr>>(&mut self, arg: S) -> &mut Command {
-        self.0.arg(arg);
+        self.command.arg(arg);
         self
     }
     pub fn args<I, S>(&mut self, args: I) -> &mut Command
         where
             I: IntoIterator<Item = S>,
             S: AsRef<OsStr> {
-        self.0.args(args);
+        self.command.args(args);
+        self
+    }
+    ///Sets an environment variable for the child; compare with [std::process::Command::env]
+    pub fn env<K: AsRef<OsStr>, V: AsRef<OsStr>>(&mut self, key: K, val: V) -> &mut Command {
+        self.command.env(key, val);
+        self
+    }
+    ///Sets several environment variables for the child; compare with [std::process::Command::envs]
+    pub fn envs<I, K, V>(&mut self, vars: I) -> &mut Command
+        where
+            I: IntoIterator<Item = (K, V)>,
+            K: AsRef<OsStr>,
+            V: AsRef<OsStr> {
+        self.command.envs(vars);
+        self
+    }
+    ///Removes an environment variable from the child; compare with [std::process::Command::env_remove]
+    pub fn env_remove<K: AsRef<OsStr>>(&mut self, key: K) -> &mut Command {
+        self.command.env_remove(key);
+        self
+    }
+    ///Starts the child with an empty environment; compare with [std::process::Command::env_clear]
+    pub fn env_clear(&mut self) -> &mut Command {
+        self.command.env_clear();
+        self
+    }
+    ///Starts the child with an empty environment, except for the named variables, which are copied from ours.
+    ///
+    /// Variables we don't have are skipped.  This is useful for reproducible builds, where the child shouldn't
+    /// depend on whatever happens to be in the caller's environment.  Variables set with [Command::env] afterwards are added as usual.
+    pub fn env_allowlist<I, K>(&mut self, keys: I) -> &mut Command
+        where
+            I: IntoIterator<Item = K>,
+            K: AsRef<OsStr> {
+        self.command.env_clear();
+        for key in keys {
+            if let Some(value) = std::env::var_os(key.as_ref()) {
+                self.command.env(key, value);
+            }
+        }
         self
     }
+    ///Sets the child's working directory; compare with [std::process::Command::current_dir]
+    pub fn current_dir<P: AsRef<Path>>(&mut self, dir: P) -> &mut Command {
+        self.command.current_dir(dir.as_ref());
+        self.current_dir = Some(dir.as_ref().to_path_buf());
+        self
+    }
+    ///Checks that the [Command::current_dir] is a directory before launching the process.
+    ///
+    /// If it isn't, launching fails with [Error::InvalidCurrentDir], rather than an opaque [Error::IOError] from the OS.
+    pub fn validate_current_dir(&mut self, validate: bool) -> &mut Command {
+        self.validate_current_dir = validate;
+        self
+    }
+    ///Configures the child's stdin; compare with [std::process::Command::stdin]
+    pub fn stdin<T: Into<Stdio>>(&mut self, cfg: T) -> &mut Command {
+        self.command.stdin(cfg);
+        self.input = None;
+        self
+    }
+    ///Writes the bytes to the child's stdin, then closes it.
+    ///
+    /// Input is written in the background, concurrently with reading any output, so large inputs can't deadlock
+    /// against a process that is blocked writing to a full output pipe.
+    pub fn stdin_bytes(&mut self, bytes: Vec<u8>) -> &mut Command {
+        self.command.stdin(Stdio::piped());
+        self.input = Some(Input::Bytes(bytes));
+        self
+    }
+    ///Connects the file to the child's stdin.
+    ///
+    /// The child reads the file directly, so nothing is copied through this process.
+    pub fn stdin_file(&mut self, file: std::fs::File) -> &mut Command {
+        self.command.stdin(file);
+        self.input = None;
+        self
+    }
+    ///Streams input into the child's stdin as it becomes available.
+    ///
+    /// Write chunks with the returned [StdinWriter], and drop it to close stdin.  The stream is used by the next launch only.
+    pub fn stdin_stream(&mut self) -> StdinWriter {
+        let (writer, input) = StdinWriter::new();
+        self.command.stdin(Stdio::piped());
+        self.input = Some(input);
+        writer
+    }
+    ///Configures the child's stdout; compare with [std::process::Command::stdout]
+    pub fn stdout<T: Into<Stdio>>(&mut self, cfg: T) -> &mut Command {
+        self.command.stdout(cfg);
+        self
+    }
+    ///Configures the child's stderr; compare with [std::process::Command::stderr]
+    pub fn stderr<T: Into<Stdio>>(&mut self, cfg: T) -> &mut Command {
+        self.command.stderr(cfg);
+        self
+    }
+    ///Limits how long [Command::status] and [Command::output] wait for the process.
+    ///
+    /// If the process is still running when the time is up, it is killed and reaped, and [Error::Timeout] is returned.
+    /// Processes it launched are not killed, but [Command::output] doesn't wait for them to close its output.
+    pub fn timeout(&mut self, duration: Duration) -> &mut Command {
+        self.timeout = Some(duration);
+        self
+    }
+    ///Limits how much stdout [Command::output] keeps in memory.
     #[cfg(feature="output")]
-    pub async fn output<'a,O: Into<OSOptions<'a>>>(&mut self, options: O) -> std::result::Result<Output, crate::Error> {
-        use std::process::Stdio;
-        self.0.stdout(Stdio::piped());
-        self.0.stderr(Stdio::piped());
-        let spawned = self.0.spawn()?;
-        Ok(Output::from_child(spawned,options.into()).await?)
+    pub fn stdout_limit(&mut self, limit: OutputLimit) -> &mut Command {
+        self.capture.stdout_limit = Some(limit);
+        self
+    }
+    ///Limits how much stderr [Command::output] keeps in memory.
+    #[cfg(feature="output")]
+    pub fn stderr_limit(&mut self, limit: OutputLimit) -> &mut Command {
+        self.capture.stderr_limit = Some(limit);
+        self
     }
-    pub async fn status(&mut self, _priority: kiruna::Priority) -> Result<ExitStatus, Error> {
-        //todo: use priority
-        let spawned = self.0.spawn()?;
-        use crate::waitpid::ProcessFuture;
-        #[cfg(target_os = "macos")]
-        type Pid = i32;
+    ///Copies stdout to the destination as [Command::output] reads it, in addition to capturing it.
+    ///
+    /// The destination is a file, or a pipe converted into one, and it's written with the same async IO as the output is read.
+    /// Each chunk is written before the next one is read, so once the output is collected, the destination has all of it.
+    /// A failed write fails [Command::output].
+    #[cfg(feature="output")]
+    pub fn tee_stdout(&mut self, destination: std::fs::File) -> &mut Command {
+        self.capture.stdout_tee = Some(destination);
+        self
+    }
+    ///Copies stderr to the destination as [Command::output] reads it, in addition to capturing it.  See [Command::tee_stdout].
+    #[cfg(feature="output")]
+    pub fn tee_stderr(&mut self, destination: std::fs::File) -> &mut Command {
+        self.capture.stderr_tee = Some(destination);
+        self
+    }
+    ///Runs the process, capturing its stdout and stderr.
+    ///
+    /// The priority applies both to the process and to reading its output.
+    #[cfg(feature="output")]
+    pub async fn output(&mut self, priority: Priority) -> std::result::Result<Output, crate::Error> {
+        #[cfg(not(target_os = "linux"))]
+        return self.output_with_options(priority, priority).await;
+        #[cfg(target_os = "linux")]
+        {
+            self.command.stdout(Stdio::piped());
+            self.command.stderr(Stdio::piped());
+            let spawned = self.spawn(priority)?;
+            //Linux waits on pipes with epoll; see [crate::pipe]
+            Output::from_child_captured(spawned, &self.capture, self.timeout, priority).await
+        }
+    }
+    ///Like [Command::output], but reads the output with the kiruna options, while the process runs at the priority.
+    ///
+    /// The options only apply when the output is read in one go, that is, without limits, tees or a timeout.  Linux
+    /// doesn't read pipes with kiruna, so this isn't available there.
+    #[cfg(all(feature="output", not(target_os = "linux")))]
+    pub async fn output_with_options<'a,O: Into<OSOptions<'a>>>(&mut self, priority: Priority, options: O) -> std::result::Result<Output, crate::Error> {
+        self.command.stdout(Stdio::piped());
+        self.command.stderr(Stdio::piped());
+        let spawned = self.spawn(priority)?;
+        if self.capture.is_plain() && self.timeout.is_none() {
+            return Output::from_child(spawned, options.into()).await
+        }
+        //limits, tees and timeouts need the output chunk by chunk, which is read at the process's priority
+        Output::from_child_captured(spawned, &self.capture, self.timeout, priority).await
+    }
+    ///Runs the process, capturing stdout and stderr together in the order they were written, like `2>&1`.
+    ///
+    /// On Unix, both are connected to the same pipe, so the order is exact.  On Windows, output is merged in the order
+    /// it is read, which can differ when both streams are written at nearly the same time.
+    #[cfg(feature="output")]
+    pub async fn output_combined(&mut self, priority: Priority) -> Result<CombinedOutput, Error> {
+        #[cfg(any(target_os = "macos", target_os = "linux"))]
+        {
+            let (read, write) = crate::pipe::anonymous()?;
+            self.command.stdout(write.try_clone()?);
+            self.command.stderr(write);
+            let spawned = self.spawn(priority);
+            //the command holds the write end until we replace it, and we won't see the end of output until it closes
+            self.command.stdout(Stdio::piped());
+            self.command.stderr(Stdio::piped());
+            CombinedOutput::from_child(spawned?, Some(read), self.timeout, priority).await
+        }
         #[cfg(target_os = "windows")]
-        type Pid = u32;
-        let future = ProcessFuture::new(spawned.id() as Pid);
-        #[cfg(target_os="macos")]
-        use std::os::unix::process::ExitStatusExt;
+        {
+            self.command.stdout(Stdio::piped());
+            self.command.stderr(Stdio::piped());
+            let spawned = self.spawn(priority)?;
+            CombinedOutput::from_child(spawned, None, self.timeout, priority).await
+        }
+    }
+    ///Launches the process, returning a handle to manage it.
+    ///
+    /// On Unix, the priority is applied to the process before it starts:
+    /// * `UserWaiting` leaves the process at our own priority.
+    /// * `Testing` lowers it: `nice` 10, and on Linux, the lowest best-effort IO priority and `SCHED_BATCH`.
+    ///
+    /// On Windows, it is not yet used.
+    pub fn spawn(&mut self, priority: Priority) -> Result<Child, Error> {
+        #[cfg(any(target_os = "macos", target_os = "linux"))]
+        self.priority.set(&mut self.command, priority);
         #[cfg(target_os = "windows")]
-        use std::os::windows::process::ExitStatusExt;
-        Ok(ExitStatus::from_raw(future.await))
+        let _ = priority;
+        if let (true, Some(dir)) = (self.validate_current_dir, &self.current_dir) {
+            if !dir.is_dir() {
+                return Err(Error::InvalidCurrentDir(dir.clone()))
+            }
+        }
+        let input = Input::for_launch(&mut self.input);
+        let spawned = self.command.spawn().map_err(|e| self.error(None, &[], e.into()))?;
+        let mut child = Child::new(spawned);
+        if let Some(input) = input {
+            child.write_stdin(vec![input], priority);
+        }
+        Ok(child)
+    }
+    ///Checks the status of a process launched from this command, describing the command if it failed.
+    ///
+    /// Compare with [crate::ExitStatus::check_err], which says only how the process ended.
+    pub fn check(&self, status: ExitStatus) -> Result<(), Error> {
+        match status.check_err() {
+            Ok(()) => Ok(()),
+            Err(e) => Err(self.error(Some(status), &[], e)),
+        }
+    }
+    ///Like [Command::check], but includes the end of stderr in the error.
+    #[cfg(feature="output")]
+    pub fn check_output(&self, output: &Output) -> Result<(), Error> {
+        match output.status.check_err() {
+            Ok(()) => Ok(()),
+            Err(e) => Err(self.error(Some(output.status), output.stderr.as_slice(), e)),
+        }
+    }
+    ///Runs the process and checks that it succeeded.
+    ///
+    /// This is [Command::status] followed by [Command::check].
+    pub async fn run_ok(&mut self, priority: Priority) -> Result<(), Error> {
+        let status = self.status(priority).await?;
+        self.check(status)
+    }
+    ///Runs the process, capturing its output, and checks that it succeeded.
+    ///
+    /// This is [Command::output] followed by [Command::check_output], so a failure includes the end of stderr.
+    #[cfg(feature="output")]
+    pub async fn output_ok(&mut self, priority: Priority) -> Result<Output, Error> {
+        let output = self.output(priority).await?;
+        self.check_output(&output)?;
+        Ok(output)
+    }
+    ///Wraps the error in a [CommandError] describing this command
+    fn error(&self, status: Option<ExitStatus>, stderr: &[u8], error: Error) -> Error {
+        let stderr_tail = stderr[stderr.len().saturating_sub(STDERR_TAIL)..].to_vec();
+        Error::Command(Box::new(CommandError {
+            program: self.command.get_program().to_owned(),
+            args: self.command.get_args().map(|a| a.to_owned()).collect(),
+            current_dir: self.current_dir.clone(),
+            status,
+            stderr_tail,
+            error,
+        }))
+    }
+    pub async fn status(&mut self, priority: Priority) -> Result<ExitStatus, Error> {
+        let mut child = self.spawn(priority)?;
+        let duration = match self.timeout {
+            None => return child.wait(priority).await,
+            Some(duration) => duration
+        };
+        let waited = crate::timer::timeout(Box::pin(child.wait(priority)), duration).await;
+        match waited {
+            Some(result) => result,
+            None => {
+                child.kill()?;
+                //input the process didn't read is moot now, so errors writing it aren't reported
+                child.exited().await;
+                Err(Error::Timeout { stdout: Vec::new(), stderr: Vec::new() })
+            }
+        }
     }
 }
 
+#[cfg(test)]
+mod test {
+    use crate::{Command, Error};
+    use kiruna::Priority;
+    use std::time::Duration;
+
+    #[cfg(any(target_os = "macos", target_os = "linux"))]
+    #[test] fn status_timeout() {
+        let _guard = crate::waitpid::test::TEST_SEMAPHORE.lock();
+        let mut c = Command::new("sleep");
+        let status = c.arg("10").timeout(Duration::from_millis(100)).status(Priority::Testing);
+        match kiruna::test::test_await(status, Duration::from_secs(2)) {
+            Err(Error::Timeout { .. }) => {},
+            other => panic!("Unexpected result {:?}", other),
+        }
+        //a process that finishes in time reports its status as usual
+        let mut c = Command::new("true");
+        let status = c.timeout(Duration::from_secs(2)).status(Priority::Testing);
+        assert!(kiruna::test::test_await(status, Duration::from_secs(2)).unwrap().success());
+    }
+
+    #[cfg(any(target_os = "macos", target_os = "linux"))]
+    #[test] fn env_allowlist() {
+        let _guard = crate::waitpid::test::TEST_SEMAPHORE.lock();
+        assert!(std::env::var_os("HOME").is_some());
+        let mut c = Command::new("/bin/sh");
+        c.env_allowlist(["PATH", "COMMAND_RS_NOT_SET"]).env("FOO", "bar");
+        c.args(["-c", r#"test -n "$PATH" && test -z "$HOME" && test "$FOO" = bar && test -z "${COMMAND_RS_NOT_SET+set}""#]);
+        let status = kiruna::test::test_await(c.status(Priority::Testing), Duration::from_secs(2)).unwrap();
+        assert!(status.success());
+    }
+
+    #[cfg(any(target_os = "macos", target_os = "linux"))]
+    #[test] fn current_dir() {
+        let _guard = crate::waitpid::test::TEST_SEMAPHORE.lock();
+        let mut c = Command::new("/bin/sh");
+        c.args(["-c", r#"test "$(pwd)" = /"#]).current_dir("/").validate_current_dir(true);
+        let status = kiruna::test::test_await(c.status(Priority::Testing), Duration::from_secs(2)).unwrap();
+        assert!(status.success());
+
+        c.current_dir("/command-rs/does/not/exist");
+        match c.spawn(Priority::Testing) {
+            Err(Error::InvalidCurrentDir(dir)) => assert_eq!(dir, std::path::Path::new("/command-rs/does/not/exist")),
+            other => panic!("Unexpected result {:?}", other),
+        }
+    }
+
+    #[cfg(any(target_os = "macos", target_os = "linux"))]
+    #[test] fn stdin() {
+        let _guard = crate::waitpid::test::TEST_SEMAPHORE.lock();
+        let mut c = Command::new("/bin/sh");
+        c.args(["-c", r#"read x; test "$x" = hello"#]).stdin_bytes(b"hello\n".to_vec());
+        let status = kiruna::test::test_await(c.status(Priority::Testing), Duration::from_secs(2)).unwrap();
+        assert!(status.success());
+
+        //more than fits in a pipe buffer
+        let mut c = Command::new("/bin/sh");
+        c.args(["-c", r#"test "$(wc -c)" -eq 1048576"#]).stdin_bytes(vec![b'x'; 1 << 20]);
+        let status = kiruna::test::test_await(c.status(Priority::Testing), Duration::from_secs(2)).unwrap();
+        assert!(status.success());
+
+        let mut c = Command::new("/bin/sh");
+        c.args(["-c", r#"read x; test "$x" = hello"#]);
+        let writer = c.stdin_stream();
+        let mut child = c.spawn(Priority::Testing).unwrap();
+        kiruna::test::test_await(writer.write(b"hel".to_vec()), Duration::from_secs(1)).unwrap();
+        kiruna::test::test_await(writer.write(b"lo\n".to_vec()), Duration::from_secs(1)).unwrap();
+        drop(writer);
+        let status = kiruna::test::test_await(child.wait(Priority::Testing), Duration::from_secs(2)).unwrap();
+        assert!(status.success());
+    }
+
+    #[cfg(any(target_os = "macos", target_os = "linux"))]
+    #[test] fn stdin_writer_held() {
+        let _guard = crate::waitpid::test::TEST_SEMAPHORE.lock();
+        let mut c = Command::new("/bin/sh");
+        c.args(["-c", "exit 0"]);
+        let writer = c.stdin_stream();
+        let mut child = c.spawn(Priority::Testing).unwrap();
+        //nothing is written until we wait, so the queue fills up
+        for _ in 0..4 {
+            kiruna::test::test_await(writer.write(b"x".to_vec()), Duration::from_secs(1)).unwrap();
+        }
+        assert!(kiruna::test::test_poll(writer.write(b"x".to_vec())).is_pending());
+        //the process never reads, so we shouldn't wait on the writer
+        let status = kiruna::test::test_await(child.wait(Priority::Testing), Duration::from_secs(2)).unwrap();
+        assert!(status.success());
+        match kiruna::test::test_await(writer.write(b"x".to_vec()), Duration::from_secs(1)) {
+            Err(Error::IOError(e)) => assert_eq!(e.kind(), std::io::ErrorKind::BrokenPipe),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[cfg(any(target_os = "macos", target_os = "linux"))]
+    #[test] fn command_error() {
+        let _guard = crate::waitpid::test::TEST_SEMAPHORE.lock();
+        let mut c = Command::new("command-rs-does-not-exist");
+        c.arg("an arg");
+        match c.spawn(Priority::Testing) {
+            Err(Error::Command(e)) => {
+                assert_eq!(e.program, "command-rs-does-not-exist");
+                assert_eq!(e.args, ["an arg"]);
+                assert!(matches!(&e.error, Error::IOError(io) if io.kind() == std::io::ErrorKind::NotFound));
+            }
+            other => panic!("Unexpected result {:?}", other.map(|c| c.id())),
+        }
+
+        let mut c = Command::new("/bin/sh");
+        c.args(["-c", "exit 3"]).current_dir("/");
+        let status = kiruna::test::test_await(c.status(Priority::Testing), Duration::from_secs(2)).unwrap();
+        let e = c.check(status).unwrap_err();
+        assert_eq!(e.to_string(), "`/bin/sh -c \"exit 3\"` (in /) exited with code 3");
+    }
+
+    #[cfg(any(target_os = "macos", target_os = "linux"))]
+    #[test] fn run_ok() {
+        let _guard = crate::waitpid::test::TEST_SEMAPHORE.lock();
+        kiruna::test::test_await(Command::new("true").run_ok(Priority::Testing), Duration::from_secs(2)).unwrap();
+        match kiruna::test::test_await(Command::new("false").run_ok(Priority::Testing), Duration::from_secs(2)) {
+            Err(Error::Command(e)) => assert!(matches!(e.error, Error::StatusError(1))),
+            other => panic!("Unexpected result {:?}", other),
+        }
+    }
+
+    #[cfg(all(feature="output", any(target_os = "macos", target_os = "linux")))]
+    #[test] fn output_ok() {
+        let _guard = crate::waitpid::test::TEST_SEMAPHORE.lock();
+        let mut c = Command::new("/bin/sh");
+        c.args(["-c", "echo fine"]);
+        let output = kiruna::test::test_await(c.output_ok(Priority::Testing), Duration::from_secs(2)).unwrap();
+        assert_eq!(output.stdout.as_slice(), b"fine\n");
+
+        let mut c = Command::new("/bin/sh");
+        c.args(["-c", "echo broken >&2; exit 2"]);
+        match kiruna::test::test_await(c.output_ok(Priority::Testing), Duration::from_secs(2)) {
+            Err(Error::Command(e)) => {
+                assert_eq!(e.stderr_tail, b"broken\n");
+                assert!(e.to_string().ends_with("exited with code 2\nstderr:\nbroken"));
+            }
+            other => panic!("Unexpected result {:?}", other),
+        }
+    }
+}
@@ -15,9 +15,9 @@ A quick comparison of alternative crates:
 
 # Practicalities
 
-* macOS works, Windows is 'passing tests', Linux is planned but not implemented
-* The crate has two optional features: `output` (which supports redirecting stdout) and `sudo` (which can run commands with elevated privileges).  This makes
-  the crate useful for system administration and scripting situations.
+* macOS works, Windows is 'passing tests', Linux works (best on kernel 5.4 or later)
+* The crate has two main optional features: `output` (which supports redirecting stdout) and `sudo` (which can run commands with elevated privileges).  This makes
+  the crate useful for system administration and scripting situations.  `json` additionally parses output as JSON, via serde.
 * Free for noncommercial and 'small commercial' use.
 
 # Process context
@@ -25,6 +25,7 @@ A quick comparison of alternative crates:
 This crate generally implements the [Kiruna manifesto](https://github.com/drewcrawford/kiruna) for processes, (processes are out of scope
 for mainline Kiruna).  This includes:
 * processes have an associated priority, as to avoid interrupting an interactive task with some background one
+    * On Unix, the priority maps to the process's `nice` value (and on Linux, its IO priority and scheduling policy).
     * In some cases priorities are not yet used, so there may not be any effect, but these should get filled in over time.
 * command-rs generally consists of separate high-level functions that may be specific to a set of functionalities and one platform.  That is, launching a process
   and redirecting output is a different function than if not redirecting its output, and may be different on macos than windows, etc.
@@ -35,17 +36,30 @@ A design note about waiting on processes.  Arguably, the 'kiruna way' to do it i
 the right to adopt that implementation in the future if it turns out there is some benefit to it.
 
 However, I tried it, and launching a process is in general a heavy operation, the overhead of everything else is negligible so you might as well
-do something portable.  On supported platforms, `waitpid` is currently used.
+do something portable.
+
+That said, `waitpid(-1)` reaps every child of the process, including ones spawned by other libraries, so we do need to wait
+on specific processes.  On macOS, each process is watched with kqueue (`EVFILT_PROC`) and then reaped by its own pid.  On Linux,
+each process is watched with a pidfd (`pidfd_open` and `waitid(P_PIDFD)`), or where pidfds aren't available, waited on by its own thread.
+All of these are free of signals.
 
 */
 mod command;
+mod child;
 mod waitpid;
 
 #[cfg(feature="output")]
 mod output;
+#[cfg(feature="output")]
+mod parse;
 #[cfg(feature="sudo")]
 mod sudo;
 mod status;
+mod timer;
+mod pipe;
+mod stream;
+#[cfg(any(target_os = "macos", target_os = "linux"))]
+mod priority;
 
 #[cfg(target_os = "windows")]
 use winbindings::Windows::Win32::System::Diagnostics::Debug::WIN32_ERROR;
@@ -54,16 +68,41 @@ use winbindings::Windows::Win32::System::Diagnostics::Debug::WIN32_ERROR;
 #[non_exhaustive]
 #[derive(Debug)]
 pub enum Error {
-    #[cfg(any(feature="sudo",feature="output"))]
+    #[cfg(not(target_os = "linux"))]
     KirunaError(kiruna::io::stream::OSError),
     IOError(std::io::Error),
     StatusError(i32),
+    ///The process didn't exit normally, e.g. it was killed by a signal.  See [ExitStatus::check_err].
+    Terminated(Termination),
+    ///sudo didn't accept the password, so the program never ran.  Carries what sudo wrote to stderr.
+    ///
+    /// Not reported on Windows, where this is an ordinary failed status.
+    #[cfg(feature="sudo")]
+    SudoAuthentication(String),
+    ///A particular command failed; the [CommandError] describes the command and wraps the underlying error.
+    Command(Box<CommandError>),
+    ///The process ran longer than [Command::timeout] allowed, so it was killed.
+    ///
+    /// Carries whatever stdout and stderr were captured before then (empty if output wasn't captured).
+    Timeout { stdout: Vec<u8>, stderr: Vec<u8> },
+    ///The working directory given to [Command::current_dir] is not a directory.  See [Command::validate_current_dir].
+    InvalidCurrentDir(std::path::PathBuf),
+    ///The process wrote more than an [OutputLimit] with [LimitPolicy::Error] allows, so it was killed.
+    #[cfg(feature="output")]
+    OutputLimit { stream: Stream, limit: usize },
+    ///Output was not in the expected format
+    #[cfg(feature="output")]
+    ParseError(ParseError),
     #[cfg(target_os="windows")]
     WinError(WIN32_ERROR)
 }
 impl std::fmt::Display for Error {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        f.write_fmt(format_args!("{:?}",self))
+        match self {
+            Error::Terminated(termination) => f.write_fmt(format_args!("{}",termination)),
+            Error::Command(error) => f.write_fmt(format_args!("{}",error)),
+            other => f.write_fmt(format_args!("{:?}",other))
+        }
     }
 }
 impl std::error::Error for Error {}
@@ -72,24 +111,37 @@ impl From<std::io::Error> for Error {
         Error::IOError(f)
     }
 }
-#[cfg(any(feature="sudo",feature="output"))]
+#[cfg(feature="output")]
+impl From<ParseError> for Error {
+    fn from(f: ParseError) -> Self {
+        Error::ParseError(f)
+    }
+}
+#[cfg(not(target_os = "linux"))]
 impl From<kiruna::io::stream::OSError> for Error {
     fn from(f: kiruna::io::stream::OSError) -> Self {
         Self::KirunaError(f)
     }
 }
 
-pub use command::Command;
+pub use command::{Command, CommandError};
+pub use child::Child;
+pub use pipe::StdinWriter;
+pub use stream::{OutputStream, Chunk, Stream, Lines, Line};
+#[cfg(any(target_os = "macos", target_os = "linux"))]
+pub use child::Shutdown;
 use std::fmt::Formatter;
 
 #[cfg(feature="output")]
-pub use output::Output;
+pub use output::{Output, OutputBuffer, OutputLimit, LimitPolicy, CombinedOutput};
+#[cfg(feature="output")]
+pub use parse::{ParseError, ParseErrorKind};
 
 
-pub use status::ExitStatus;
+pub use status::{ExitStatus, Termination};
 
 #[cfg(test)] pub fn test_is_present() {}
-#[cfg(feature="sudo")] pub use sudo::Sudo;
+#[cfg(feature="sudo")] pub use sudo::{Sudo, Password};
 
 
 
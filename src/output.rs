@@ -1,66 +1,348 @@
-use std::process::{ExitStatus, Child};
-use crate::waitpid::ProcessFuture;
+use std::borrow::Cow;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use std::process::{ExitStatus};
+use std::time::Duration;
+use crate::child::Child;
+use crate::pipe::Event;
+use crate::stream::{OutputStream, Stream};
+use kiruna::Priority;
+#[cfg(not(target_os = "linux"))]
 use kiruna::io::stream::read::{Read, OSOptions};
 use crate::Error;
 
-pub struct OutputBuffer(kiruna::io::stream::read::ContiguousBuffer);
+pub(crate) enum Storage {
+    ///Read by kiruna in one go
+    #[cfg(not(target_os = "linux"))]
+    Kiruna(kiruna::io::stream::read::ContiguousBuffer),
+    ///Collected chunk by chunk, e.g. to apply an [OutputLimit]
+    Owned(Vec<u8>),
+}
+
+pub struct OutputBuffer {
+    pub(crate) storage: Storage,
+    pub(crate) dropped: u64,
+}
+impl std::fmt::Debug for OutputBuffer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("OutputBuffer").field("len", &self.as_slice().len()).field("dropped", &self.dropped).finish()
+    }
+}
 impl OutputBuffer {
     pub fn as_slice(&self) -> &[u8] {
-        self.0.as_slice()
+        match &self.storage {
+            #[cfg(not(target_os = "linux"))]
+            Storage::Kiruna(buffer) => buffer.as_slice(),
+            Storage::Owned(vec) => vec.as_slice(),
+        }
+    }
+    ///Number of bytes the process wrote that were discarded due to an [OutputLimit]
+    pub fn bytes_dropped(&self) -> u64 {
+        self.dropped
+    }
+    ///The output as UTF-8
+    pub fn to_str(&self) -> Result<&str, std::str::Utf8Error> {
+        std::str::from_utf8(self.as_slice())
+    }
+    ///The output as UTF-8, replacing invalid sequences with U+FFFD
+    pub fn to_string_lossy(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(self.as_slice())
+    }
+    ///The output without trailing newlines, the way a shell's `$(...)` would have it.
+    ///
+    /// Both `\n` and `\r\n` are removed.
+    pub fn trimmed(&self) -> &[u8] {
+        let slice = self.as_slice();
+        let end = slice.iter().rposition(|b| *b != b'\n' && *b != b'\r').map(|p| p + 1).unwrap_or(0);
+        &slice[..end]
     }
+    ///Like [OutputBuffer::to_str], without trailing newlines.  See [OutputBuffer::trimmed].
+    pub fn to_str_trimmed(&self) -> Result<&str, std::str::Utf8Error> {
+        std::str::from_utf8(self.trimmed())
+    }
+    ///The lines of output, without their `\n` or `\r\n`.
+    ///
+    /// A final line without a newline is included; a trailing newline does not produce an empty line.  Compare with
+    /// [str::lines], which this works like but without requiring UTF-8.
+    pub fn lines(&self) -> impl Iterator<Item=&[u8]> {
+        let slice = self.as_slice();
+        let empty = slice.is_empty();
+        let slice = slice.strip_suffix(b"\n").unwrap_or(slice);
+        slice.split(|b| *b == b'\n').filter(move |_| !empty).map(|line| line.strip_suffix(b"\r").unwrap_or(line))
+    }
+    ///Decodes output in the OEM code page, which is what most console tools (e.g. `ipconfig`) write when their output is piped.
+    #[cfg(target_os = "windows")]
+    pub fn to_string_oem(&self) -> Result<String, Error> {
+        use winbindings::Windows::Win32::Globalization::{GetOEMCP, MultiByteToWideChar, MULTI_BYTE_TO_WIDE_CHAR_FLAGS};
+        use winbindings::Windows::Win32::Foundation::{PSTR, PWSTR};
+        use winbindings::Windows::Win32::System::Diagnostics::Debug::GetLastError;
+        let slice = self.as_slice();
+        if slice.is_empty() {
+            return Ok(String::new())
+        }
+        //first call finds the length, the second converts
+        let code_page = unsafe{ GetOEMCP() };
+        let len = unsafe{ MultiByteToWideChar(code_page, MULTI_BYTE_TO_WIDE_CHAR_FLAGS(0), PSTR(slice.as_ptr() as *mut u8), slice.len() as i32, PWSTR(std::ptr::null_mut()), 0) };
+        if len == 0 {
+            return Err(Error::WinError(unsafe{ GetLastError() }))
+        }
+        let mut wide = vec![0u16; len as usize];
+        let len = unsafe{ MultiByteToWideChar(code_page, MULTI_BYTE_TO_WIDE_CHAR_FLAGS(0), PSTR(slice.as_ptr() as *mut u8), slice.len() as i32, PWSTR(wide.as_mut_ptr()), len) };
+        if len == 0 {
+            return Err(Error::WinError(unsafe{ GetLastError() }))
+        }
+        wide.truncate(len as usize);
+        Ok(String::from_utf16_lossy(&wide))
+    }
+    ///Decodes output written as UTF-16LE, e.g. by `cmd /u` or PowerShell.
+    ///
+    /// A trailing odd byte is an error.
+    #[cfg(target_os = "windows")]
+    pub fn to_string_utf16(&self) -> Result<String, std::string::FromUtf16Error> {
+        let slice = self.as_slice();
+        let wide: Vec<u16> = slice.chunks(2).map(|c| if c.len() == 2 { u16::from_le_bytes([c[0], c[1]]) } else { 0xD800 }).collect();
+        String::from_utf16(&wide)
+    }
+    ///The buffer as dispatch data.
+    ///
+    /// This is `None` when the output was collected chunk by chunk, e.g. when an [OutputLimit] was used.
     #[cfg(target_os = "macos")]
-    pub fn as_dispatch_data(&self) -> &dispatchr::data::Unmanaged { self.0.as_dispatch_data() }
+    pub fn as_dispatch_data(&self) -> Option<&dispatchr::data::Unmanaged> {
+        match &self.storage {
+            Storage::Kiruna(buffer) => Some(buffer.as_dispatch_data()),
+            Storage::Owned(_) => None,
+        }
+    }
 }
 ///compare with [std::process::Output]
+#[derive(Debug)]
 pub struct Output {
     pub status: ExitStatus,
     pub stdout: OutputBuffer,
-    pub stderr: OutputBuffer
+    pub stderr: OutputBuffer,
+    ///Whether any output was discarded due to an [OutputLimit].  See [OutputBuffer::bytes_dropped] for details.
+    pub truncated: bool,
 }
 
-impl Output {
-    pub(crate) async fn from_child<'a,O: Into<OSOptions<'a>> + Clone>(child: Child,options:O) -> Result<Output,Error> {
-        #[cfg(not(any(target_os = "windows",target_os="macos")))]
-        complie_error!("Unsupported");
+///What to do when a process writes more than an [OutputLimit] allows
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitPolicy {
+    ///Kill the process and fail with [Error::OutputLimit]
+    Error,
+    ///Keep the first bytes, and discard the rest
+    Head,
+    ///Keep the last bytes, discarding older output as newer output arrives
+    Tail,
+}
+
+///Caps how much of a stream [crate::Command::output] keeps in memory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OutputLimit {
+    pub max_bytes: usize,
+    pub policy: LimitPolicy,
+}
+
+///How [crate::Command::output] collects output, beyond the defaults
+#[derive(Default)]
+pub(crate) struct Capture {
+    pub(crate) stdout_limit: Option<OutputLimit>,
+    pub(crate) stderr_limit: Option<OutputLimit>,
+    ///Duplicated for each launch
+    pub(crate) stdout_tee: Option<std::fs::File>,
+    pub(crate) stderr_tee: Option<std::fs::File>,
+}
+impl Capture {
+    ///Whether kiruna can read everything in one go
+    #[cfg(not(target_os = "linux"))]
+    pub(crate) fn is_plain(&self) -> bool {
+        self.stdout_limit.is_none() && self.stderr_limit.is_none() && self.stdout_tee.is_none() && self.stderr_tee.is_none()
+    }
+}
+
+///Collects one stream's output, applying its limit
+#[derive(Debug)]
+struct Collector {
+    stream: Stream,
+    limit: Option<OutputLimit>,
+    kept: VecDeque<u8>,
+    dropped: u64,
+}
+impl Collector {
+    fn new(stream: Stream, limit: Option<OutputLimit>) -> Collector {
+        Collector { stream, limit, kept: VecDeque::new(), dropped: 0 }
+    }
+    fn push(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        let limit = match self.limit {
+            None => {
+                self.kept.extend(bytes);
+                return Ok(())
+            }
+            Some(limit) => limit
+        };
+        let room = limit.max_bytes.saturating_sub(self.kept.len());
+        match limit.policy {
+            LimitPolicy::Error => {
+                if bytes.len() > room {
+                    return Err(Error::OutputLimit { stream: self.stream, limit: limit.max_bytes })
+                }
+                self.kept.extend(bytes);
+            }
+            LimitPolicy::Head => {
+                let keep = room.min(bytes.len());
+                self.kept.extend(&bytes[..keep]);
+                self.dropped += (bytes.len() - keep) as u64;
+            }
+            LimitPolicy::Tail => {
+                //no point copying bytes that will be discarded right away
+                let start = bytes.len().saturating_sub(limit.max_bytes);
+                self.kept.extend(&bytes[start..]);
+                let excess = self.kept.len() - limit.max_bytes.min(self.kept.len());
+                self.kept.drain(..excess);
+                self.dropped += (start + excess) as u64;
+            }
+        }
+        Ok(())
+    }
+    fn into_buffer(self) -> OutputBuffer {
+        OutputBuffer { storage: Storage::Owned(self.kept.into()), dropped: self.dropped }
+    }
+}
+
+///Reads both streams until they close.  If there's no collector for stderr, it goes to the stdout collector.
+async fn collect(stream: &mut OutputStream, stdout: &mut Collector, mut stderr: Option<&mut Collector>) -> Result<(), Error> {
+    while let Some(event) = stream.next_event().await {
+        match event {
+            Event::Data(Stream::Stdout, bytes, _) => stdout.push(&bytes)?,
+            Event::Data(Stream::Stderr, bytes, _) => match &mut stderr {
+                Some(stderr) => stderr.push(&bytes)?,
+                None => stdout.push(&bytes)?,
+            },
+            Event::End(_) => {},
+            Event::Failed(error) => return Err(error)
+        }
+    }
+    Ok(())
+}
 
+impl Output {
+    ///Reads both pipes with kiruna, to the end.
+    ///
+    /// This can't stop partway, so output with a timeout is collected with [Output::from_child_captured] instead, as is all
+    /// output on Linux.
+    #[cfg(not(target_os = "linux"))]
+    pub(crate) async fn from_child<'a,O: Into<OSOptions<'a>> + Clone>(mut child: Child,options:O) -> Result<Output,Error> {
         #[cfg(target_os = "macos")]
-        let (status_arg, output_arg, error_arg) = {
+        let (output_arg, error_arg) = {
             use std::os::unix::io::IntoRawFd;
-            let status_arg = child.id() as i32;
-            let output_arg = child.stdout.unwrap().into_raw_fd();
-            let error_arg = child.stderr.unwrap().into_raw_fd();
-            (status_arg,output_arg,error_arg)
+            let output_arg = child.stdout.take().unwrap().into_raw_fd();
+            let error_arg = child.stderr.take().unwrap().into_raw_fd();
+            (output_arg,error_arg)
         };
         #[cfg(target_os = "windows")]
-            let (status_arg, output_arg, error_arg) = {
-            let status_arg = child.id() as u32;
-            let output_arg = child.stdout.unwrap();
-            let error_arg = child.stderr.unwrap();
-            (status_arg,output_arg,error_arg)
+            let (output_arg, error_arg) = {
+            let output_arg = child.stdout.take().unwrap();
+            let error_arg = child.stderr.take().unwrap();
+            (output_arg,error_arg)
         };
 
         //pipe input and output
-        let status = ProcessFuture::new(status_arg);
         let output_task = Read::new(output_arg);
         let error_task = Read::new(error_arg);
         let output_future = output_task.all(options.clone().into());
         let error_future = error_task.all(options.into());
-        let joined_io = kiruna::join::try_join2(output_future,error_future);
-
-        let all = kiruna::join::join2(status,joined_io);
-
-        let result = all.await;
-        let nonerr = result.1.map_err(|e| e.merge())?;
-        #[cfg(target_os = "windows")]
-        use std::os::windows::process::ExitStatusExt;
-        #[cfg(target_os = "macos")]
-        use std::os::unix::process::ExitStatusExt;
+        let input = child.input();
+        let result = crate::pipe::alongside(input.as_ref(), kiruna::join::try_join2(output_future,error_future)).await;
+        let status = child.exited().await;
+        let nonerr = result.map_err(|e| e.merge())?;
+        child.stdin_result()?;
         Ok(Output {
-            status: ExitStatus::from_raw(result.0),
-            stdout: OutputBuffer(nonerr.0.into_contiguous()),
-            stderr: OutputBuffer(nonerr.1.into_contiguous())
+            status,
+            stdout: OutputBuffer { storage: Storage::Kiruna(nonerr.0.into_contiguous()), dropped: 0 },
+            stderr: OutputBuffer { storage: Storage::Kiruna(nonerr.1.into_contiguous()), dropped: 0 },
+            truncated: false,
         })
     }
+    ///Collects output chunk by chunk, so that it can be limited or copied elsewhere.  On Linux, this is how all output is collected.
+    pub(crate) async fn from_child_captured(mut child: Child, capture: &Capture, timeout: Option<Duration>, priority: Priority) -> Result<Output,Error> {
+        let stdout_tee = capture.stdout_tee.as_ref().map(std::fs::File::try_clone).transpose()?;
+        let stderr_tee = capture.stderr_tee.as_ref().map(std::fs::File::try_clone).transpose()?;
+        let mut stream = OutputStream::with_tees(child.stdout.take(), child.stderr.take(), stdout_tee, stderr_tee, child.input(), priority);
+        let mut stdout = Collector::new(Stream::Stdout, capture.stdout_limit);
+        let mut stderr = Collector::new(Stream::Stderr, capture.stderr_limit);
+        let collected = Box::pin(collect(&mut stream, &mut stdout, Some(&mut stderr)));
+        let (status, timed_out) = finish(&mut child, collected, timeout).await?;
+        if timed_out {
+            return Err(Error::Timeout { stdout: stdout.kept.into(), stderr: stderr.kept.into() })
+        }
+        Ok(Output {
+            status,
+            truncated: stdout.dropped > 0 || stderr.dropped > 0,
+            stdout: stdout.into_buffer(),
+            stderr: stderr.into_buffer(),
+        })
+    }
+}
+
+///How long we keep reading after killing a process that timed out
+const DRAIN: Duration = Duration::from_millis(100);
+
+///Drives collection to the end and reaps the child.
+///
+/// If collection fails, the child is killed.  If it takes longer than the timeout, the child is killed, and we keep what
+/// it wrote beforehand; the second value reports whether that happened.
+async fn finish<F: Future<Output=Result<(),Error>>>(child: &mut Child, mut collected: Pin<Box<F>>, timeout: Option<Duration>) -> Result<(ExitStatus, bool), Error> {
+    let mut timed_out = false;
+    let result = match timeout {
+        None => collected.await,
+        Some(duration) => {
+            match crate::timer::timeout(collected.as_mut(), duration).await {
+                Some(result) => result,
+                None => {
+                    timed_out = true;
+                    child.kill()?;
+                    //What the process wrote before the kill is already in the pipes, but anything it launched may hold them open
+                    //for much longer, so we only drain them briefly instead of reading to the end.
+                    crate::timer::timeout(collected.as_mut(), DRAIN).await.unwrap_or(Ok(()))
+                }
+            }
+        }
+    };
+    if let Err(error) = result {
+        child.kill()?;
+        child.exited().await;
+        return Err(error)
+    }
+    let status = child.exited().await;
+    if !timed_out {
+        child.stdin_result()?;
+    }
+    Ok((status, timed_out))
+}
+
+///Output where stdout and stderr were captured together, like `2>&1`
+#[derive(Debug)]
+pub struct CombinedOutput {
+    pub status: ExitStatus,
+    ///Both streams, in the order they were written
+    pub output: OutputBuffer,
+}
+impl CombinedOutput {
+    ///Collects from a child whose stdout and stderr are already the same pipe (see [crate::pipe::anonymous]),
+    /// or otherwise merges them in the order they arrive.
+    pub(crate) async fn from_child(mut child: Child, combined: Option<std::fs::File>, timeout: Option<Duration>, priority: Priority) -> Result<CombinedOutput,Error> {
+        let mut stream = match combined {
+            Some(combined) => OutputStream::from_pipe(combined, child.input(), priority),
+            None => OutputStream::new(child.stdout.take(), child.stderr.take(), child.input(), priority)
+        };
+        let mut output = Collector::new(Stream::Stdout, None);
+        let collected = Box::pin(collect(&mut stream, &mut output, None));
+        let (status, timed_out) = finish(&mut child, collected, timeout).await?;
+        if timed_out {
+            return Err(Error::Timeout { stdout: output.kept.into(), stderr: Vec::new() })
+        }
+        Ok(CombinedOutput { status, output: output.into_buffer() })
+    }
 }
 #[cfg(test)]
 mod test {
@@ -68,9 +350,9 @@ mod test {
     use kiruna::Priority;
 
     //note that 'echo' is builtin on windows...
-    #[cfg(target_os = "macos")]
+    #[cfg(any(target_os = "macos", target_os = "linux"))]
     #[test] fn test_output() {
-
+        let _guard = crate::waitpid::test::TEST_SEMAPHORE.lock();
 
         let mut c = Command::new("echo");
         let c2 = c.arg("foo").arg("bar").output(Priority::Testing);
@@ -78,14 +360,95 @@ mod test {
         assert_eq!(r.unwrap().stdout.as_slice(), "foo bar\n".as_bytes());
     }
 
+    #[cfg(any(target_os = "macos", target_os = "linux"))]
+    #[test] fn output_limit() {
+        use crate::{OutputLimit, LimitPolicy, Error, Stream};
+        let _guard = crate::waitpid::test::TEST_SEMAPHORE.lock();
+        let mut c = Command::new("head");
+        c.args(["-c", "100000", "/dev/zero"]).stdout_limit(OutputLimit { max_bytes: 10, policy: LimitPolicy::Head });
+        let r = kiruna::test::test_await(c.output(Priority::Testing), std::time::Duration::from_secs(1)).unwrap();
+        assert_eq!(r.stdout.as_slice(), [0; 10]);
+        assert_eq!(r.stdout.bytes_dropped(), 99990);
+        assert!(r.truncated);
+
+        let mut c = Command::new("printf");
+        c.arg("abcdef").stdout_limit(OutputLimit { max_bytes: 3, policy: LimitPolicy::Tail });
+        let r = kiruna::test::test_await(c.output(Priority::Testing), std::time::Duration::from_secs(1)).unwrap();
+        assert_eq!(r.stdout.as_slice(), b"def");
+        assert_eq!(r.stdout.bytes_dropped(), 3);
+
+        let mut c = Command::new("head");
+        c.args(["-c", "100000", "/dev/zero"]).stdout_limit(OutputLimit { max_bytes: 10, policy: LimitPolicy::Error });
+        match kiruna::test::test_await(c.output(Priority::Testing), std::time::Duration::from_secs(1)) {
+            Err(Error::OutputLimit { stream: Stream::Stdout, limit: 10 }) => {},
+            other => panic!("Unexpected result {:?}", other),
+        }
+    }
+
+    #[cfg(any(target_os = "macos", target_os = "linux"))]
+    #[test] fn output_combined() {
+        let _guard = crate::waitpid::test::TEST_SEMAPHORE.lock();
+        let mut c = Command::new("/bin/sh");
+        c.args(["-c", "echo 1; echo 2 >&2; echo 3; echo 4 >&2"]);
+        let r = kiruna::test::test_await(c.output_combined(Priority::Testing), std::time::Duration::from_secs(1)).unwrap();
+        assert!(r.status.success());
+        assert_eq!(r.output.as_slice(), b"1\n2\n3\n4\n");
+    }
+
+    #[cfg(any(target_os = "macos", target_os = "linux"))]
+    #[test] fn tee() {
+        let _guard = crate::waitpid::test::TEST_SEMAPHORE.lock();
+        let path = std::env::temp_dir().join(format!("command-rs-tee-{}", std::process::id()));
+        let file = std::fs::File::create(&path).unwrap();
+        let mut c = Command::new("/bin/sh");
+        c.args(["-c", "echo out; echo err >&2"]).tee_stdout(file);
+        let r = kiruna::test::test_await(c.output(Priority::Testing), std::time::Duration::from_secs(1)).unwrap();
+        assert_eq!(r.stdout.as_slice(), b"out\n");
+        assert_eq!(r.stderr.as_slice(), b"err\n");
+        assert_eq!(std::fs::read(&path).unwrap(), b"out\n");
+
+        //opened read-only, so the write fails
+        let mut c = Command::new("/bin/sh");
+        c.args(["-c", "echo out"]).tee_stdout(std::fs::File::open(&path).unwrap());
+        assert!(kiruna::test::test_await(c.output(Priority::Testing), std::time::Duration::from_secs(1)).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(any(target_os = "macos", target_os = "linux"))]
+    #[test] fn output_timeout() {
+        use crate::Error;
+        let _guard = crate::waitpid::test::TEST_SEMAPHORE.lock();
+        //the sleep outlives the shell, and holds the output pipes open
+        let mut c = Command::new("/bin/sh");
+        c.args(["-c", "echo partial; echo err >&2; sleep 10; true"]).timeout(std::time::Duration::from_millis(200));
+        match kiruna::test::test_await(c.output(Priority::Testing), std::time::Duration::from_secs(2)) {
+            Err(Error::Timeout { stdout, stderr }) => {
+                assert_eq!(stdout, b"partial\n");
+                assert_eq!(stderr, b"err\n");
+            }
+            other => panic!("Unexpected result {:?}", other),
+        }
+    }
+
+    #[test] fn text() {
+        use super::{OutputBuffer, Storage};
+        let buffer = |bytes: &[u8]| OutputBuffer { storage: Storage::Owned(bytes.to_vec()), dropped: 0 };
+        assert_eq!(buffer(b"a\r\nb\n\n").to_str_trimmed().unwrap(), "a\r\nb");
+        assert_eq!(buffer(b"a\xff").to_string_lossy(), "a\u{fffd}");
+        assert!(buffer(b"a\xff").to_str().is_err());
+        assert_eq!(buffer(b"a\r\n\nb").lines().collect::<Vec<_>>(), [&b"a"[..], b"", b"b"]);
+        assert_eq!(buffer(b"a\n").lines().collect::<Vec<_>>(), [b"a"]);
+        assert_eq!(buffer(b"").lines().count(), 0);
+        assert_eq!(buffer(b"\n").lines().collect::<Vec<_>>(), [b""]);
+    }
+
     #[cfg(target_os = "windows")]
     #[test] fn test_output() {
 
         let mut c = Command::new("ipconfig");
         let c2 = c.output(Priority::Testing);
         let r = kiruna::test::test_await(c2,std::time::Duration::from_secs(3));
-        let slice = r.as_ref().unwrap().stdout.as_slice();
-        let str = std::str::from_utf8(slice).unwrap();
+        let str = r.as_ref().unwrap().stdout.to_string_oem().unwrap();
         assert!(str.contains("Windows IP Configuration"));
     }
 }
@@ -0,0 +1,151 @@
+/*!
+Parsers for the machine-readable formats tools commonly print, e.g. `lsblk -J`, `find -print0` or `systemctl show`.
+*/
+use std::collections::HashMap;
+use crate::output::{Output, OutputBuffer};
+
+///How many bytes of output a [ParseError] quotes
+const SNIPPET_LEN: usize = 64;
+
+///What went wrong in a [ParseError]
+#[non_exhaustive]
+#[derive(Debug)]
+pub enum ParseErrorKind {
+    ///The output is not valid UTF-8
+    Utf8(std::str::Utf8Error),
+    ///A `key=value` line has no `=`
+    MissingSeparator,
+    #[cfg(feature="json")]
+    Json(serde_json::Error),
+}
+
+///Output could not be parsed in the expected format
+#[derive(Debug)]
+pub struct ParseError {
+    pub kind: ParseErrorKind,
+    ///Byte offset into the output where the problem is
+    pub offset: usize,
+    ///The output around [ParseError::offset], shortened and decoded lossily, to show in messages
+    pub snippet: String,
+}
+impl ParseError {
+    fn new(kind: ParseErrorKind, slice: &[u8], offset: usize) -> ParseError {
+        let offset = offset.min(slice.len());
+        let start = offset.saturating_sub(SNIPPET_LEN / 2);
+        let end = (start + SNIPPET_LEN).min(slice.len());
+        ParseError { kind, offset, snippet: String::from_utf8_lossy(&slice[start..end]).into_owned() }
+    }
+}
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?} at byte {} near {:?}", self.kind, self.offset, self.snippet)
+    }
+}
+impl std::error::Error for ParseError {}
+
+///Decodes `len` bytes of the output at `start`, reporting errors against the whole output
+fn utf8(slice: &[u8], start: usize, len: usize) -> Result<&str, ParseError> {
+    std::str::from_utf8(&slice[start..start + len]).map_err(|e| ParseError::new(ParseErrorKind::Utf8(e), slice, start + e.valid_up_to()))
+}
+
+impl OutputBuffer {
+    ///Parses the output as JSON
+    #[cfg(feature="json")]
+    pub fn json<T: serde::de::DeserializeOwned>(&self) -> Result<T, ParseError> {
+        let slice = self.as_slice();
+        serde_json::from_slice(slice).map_err(|e| {
+            //serde_json reports lines and columns, which are 1-based
+            let offset = slice.split(|b| *b == b'\n').take(e.line().saturating_sub(1)).map(|line| line.len() + 1).sum::<usize>() + e.column().saturating_sub(1);
+            ParseError::new(ParseErrorKind::Json(e), slice, offset)
+        })
+    }
+    ///Splits NUL-terminated records, like `find -print0` writes.
+    ///
+    /// The final record may omit its NUL.  Records are bytes, since filenames needn't be UTF-8; decoding is up to the caller.
+    pub fn nul_records(&self) -> Vec<&[u8]> {
+        let slice = self.as_slice();
+        if slice.is_empty() {
+            return Vec::new()
+        }
+        slice.strip_suffix(b"\0").unwrap_or(slice).split(|b| *b == 0).collect()
+    }
+    ///Parses `key=value` lines, like `systemctl show` writes.
+    ///
+    /// Values may contain `=`; only the first one separates.  Blank lines are skipped, and if a key repeats, the last value wins.
+    pub fn key_values(&self) -> Result<HashMap<&str, &str>, ParseError> {
+        let slice = self.as_slice();
+        let mut map = HashMap::new();
+        let mut offset = 0;
+        for line in self.lines() {
+            let line_offset = offset;
+            offset += line.len() + 1;
+            if slice.get(line_offset + line.len()) == Some(&b'\r') {
+                offset += 1;
+            }
+            if line.is_empty() { continue }
+            let line = utf8(slice, line_offset, line.len())?;
+            match line.split_once('=') {
+                Some((key, value)) => { map.insert(key, value); }
+                None => return Err(ParseError::new(ParseErrorKind::MissingSeparator, slice, line_offset)),
+            }
+        }
+        Ok(map)
+    }
+}
+
+impl Output {
+    ///Parses stdout as JSON.  See [OutputBuffer::json].
+    #[cfg(feature="json")]
+    pub fn json<T: serde::de::DeserializeOwned>(&self) -> Result<T, ParseError> {
+        self.stdout.json()
+    }
+    ///Splits stdout into NUL-terminated records.  See [OutputBuffer::nul_records].
+    pub fn nul_records(&self) -> Vec<&[u8]> {
+        self.stdout.nul_records()
+    }
+    ///Parses stdout as `key=value` lines.  See [OutputBuffer::key_values].
+    pub fn key_values(&self) -> Result<HashMap<&str, &str>, ParseError> {
+        self.stdout.key_values()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::output::{OutputBuffer, Storage};
+    use super::ParseErrorKind;
+
+    fn buffer(bytes: &[u8]) -> OutputBuffer {
+        OutputBuffer { storage: Storage::Owned(bytes.to_vec()), dropped: 0 }
+    }
+
+    #[test] fn parse() {
+        let records = buffer(b"a\0b c\0");
+        assert_eq!(records.nul_records(), [&b"a"[..], b"b c"]);
+        assert!(buffer(b"").nul_records().is_empty());
+        let latin1 = buffer(b"a\0b\xff");
+        assert_eq!(latin1.nul_records(), [&b"a"[..], b"b\xff"]);
+
+        let show = buffer(b"Id=foo.service\r\n\nExecStart=/bin/x a=b\n");
+        let kv = show.key_values().unwrap();
+        assert_eq!(kv["Id"], "foo.service");
+        assert_eq!(kv["ExecStart"], "/bin/x a=b");
+        let e = buffer(b"a=1\r\nbogus\nc=3").key_values().unwrap_err();
+        assert!(matches!(e.kind, ParseErrorKind::MissingSeparator));
+        assert_eq!(e.offset, 5);
+        assert_eq!(e.snippet, "a=1\r\nbogus\nc=3");
+        let e = buffer(b"a=1\nb=\xff").key_values().unwrap_err();
+        assert!(matches!(e.kind, ParseErrorKind::Utf8(_)));
+        assert_eq!(e.offset, 6);
+    }
+
+    #[cfg(feature="json")]
+    #[test] fn json() {
+        let v: std::collections::HashMap<String, Vec<u32>> = buffer(b"{\"a\": [1, 2]}").json().unwrap();
+        assert_eq!(v["a"], [1, 2]);
+        let bad = buffer(b"{\n  \"a\": nope}");
+        let e = bad.json::<serde_json::Value>().unwrap_err();
+        assert!(matches!(e.kind, ParseErrorKind::Json(_)));
+        //serde_json gives up on the 'o', expecting 'null'
+        assert_eq!(e.offset, 10);
+    }
+}
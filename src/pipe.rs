@@ -0,0 +1,348 @@
+/*!
+Moves data between child pipes and async code.
+
+Pipes are read and written with kiruna streams, except on Linux, where we read or write once epoll reports them ready
+(see the `linux` module).  Either way, no thread is blocked on a pipe.
+*/
+use std::collections::VecDeque;
+use std::fs::File;
+use std::future::Future;
+use std::pin::Pin;
+use std::process::ChildStdin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Wake, Waker};
+use std::time::SystemTime;
+use kiruna::Priority;
+use crate::stream::Stream;
+use crate::Error;
+
+#[cfg(target_os = "linux")]
+mod linux;
+#[cfg(target_os = "linux")]
+pub(crate) use linux::{Reader, Writer};
+#[cfg(not(target_os = "linux"))]
+mod kiruna_io;
+#[cfg(not(target_os = "linux"))]
+pub(crate) use kiruna_io::{Reader, Writer};
+
+#[derive(Debug)]
+struct State<T> {
+    items: VecDeque<T>,
+    ///How many items [Sender::send_bounded] lets wait in `items`
+    capacity: usize,
+    ///Number of live [Sender]s.  Once this reaches 0, the receiver sees the end after the remaining items.
+    senders: usize,
+    ///Whether the [Receiver] is still around.  Once it's gone, nobody will take more items.
+    receiving: bool,
+    ///Receiver waiting for an item, if any
+    waker: Option<Waker>,
+    ///Senders waiting for room
+    blocked: Vec<Waker>,
+}
+impl<T> State<T> {
+    fn wake_blocked(&mut self) {
+        for waker in self.blocked.drain(..) {
+            waker.wake()
+        }
+    }
+}
+
+///Sends items to a [Receiver].
+#[derive(Debug)]
+pub(crate) struct Sender<T>(Arc<Mutex<State<T>>>);
+impl<T> Sender<T> {
+    ///Sends the item once the channel has room.  If the receiver is gone, the item comes back as the error.
+    pub(crate) fn send_bounded(&self, item: T) -> SendBounded<'_, T> {
+        SendBounded { sender: self, item: Some(item) }
+    }
+}
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.0.lock().unwrap().senders += 1;
+        Sender(self.0.clone())
+    }
+}
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let waker = {
+            let mut state = self.0.lock().unwrap();
+            state.senders -= 1;
+            state.waker.take()
+        };
+        if let Some(waker) = waker { waker.wake() }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct SendBounded<'a, T> {
+    sender: &'a Sender<T>,
+    item: Option<T>,
+}
+//we never pin the item
+impl<'a, T> Unpin for SendBounded<'a, T> {}
+impl<'a, T> Future for SendBounded<'a, T> {
+    type Output = Result<(), T>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let item = self.item.take().expect("polled after completion");
+        let waker = {
+            let mut state = self.sender.0.lock().unwrap();
+            if !state.receiving {
+                return Poll::Ready(Err(item))
+            }
+            if state.items.len() >= state.capacity {
+                state.blocked.push(cx.waker().clone());
+                drop(state);
+                self.item = Some(item);
+                return Poll::Pending
+            }
+            state.items.push_back(item);
+            state.waker.take()
+        };
+        if let Some(waker) = waker { waker.wake() }
+        Poll::Ready(Ok(()))
+    }
+}
+
+///Receives items from [Sender]s.
+#[derive(Debug)]
+pub(crate) struct Receiver<T>(Arc<Mutex<State<T>>>);
+impl<T> Receiver<T> {
+    ///Resolves to the next item, or `None` once all senders are gone and the items are used up.
+    pub(crate) fn recv(&mut self) -> Recv<'_, T> {
+        Recv(self)
+    }
+}
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        let mut state = self.0.lock().unwrap();
+        state.receiving = false;
+        state.wake_blocked();
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct Recv<'a, T>(&'a mut Receiver<T>);
+impl<'a, T> Future for Recv<'a, T> {
+    type Output = Option<T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut state = (self.0).0.lock().unwrap();
+        if let Some(item) = state.items.pop_front() {
+            state.wake_blocked();
+            return Poll::Ready(Some(item))
+        }
+        if state.senders == 0 {
+            return Poll::Ready(None)
+        }
+        state.waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+///A channel where [Sender::send_bounded] waits while `capacity` items are waiting to be received.
+pub(crate) fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    let shared = Arc::new(Mutex::new(State {
+        items: VecDeque::new(),
+        capacity,
+        senders: 1,
+        receiving: true,
+        waker: None,
+        blocked: Vec::new(),
+    }));
+    (Sender(shared.clone()), Receiver(shared))
+}
+
+///Creates a pipe, returning the read and write ends.
+///
+/// Both ends are close-on-exec, so they only reach a child when passed as its stdio.
+#[cfg(all(feature = "output", any(target_os = "macos", target_os = "linux")))]
+pub(crate) fn anonymous() -> Result<(std::fs::File, std::fs::File), std::io::Error> {
+    use std::os::unix::io::FromRawFd;
+    let mut fds = [0; 2];
+    #[cfg(target_os = "linux")]
+    let r = unsafe{ libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) };
+    #[cfg(target_os = "macos")]
+    let r = unsafe{ libc::pipe(fds.as_mut_ptr()) };
+    if r != 0 {
+        return Err(std::io::Error::last_os_error())
+    }
+    let (read, write) = unsafe{ (std::fs::File::from_raw_fd(fds[0]), std::fs::File::from_raw_fd(fds[1])) };
+    //macOS has no pipe2
+    #[cfg(target_os = "macos")]
+    for fd in &fds {
+        if unsafe{ libc::fcntl(*fd, libc::F_SETFD, libc::FD_CLOEXEC) } != 0 {
+            return Err(std::io::Error::last_os_error())
+        }
+    }
+    Ok((read, write))
+}
+
+///How many chunks a [StdinWriter] queues before [StdinWriter::write] waits for the process to read
+const STDIN_CHUNKS: usize = 4;
+
+///Data to write to a child's stdin
+#[derive(Debug)]
+pub(crate) enum Input {
+    Bytes(Vec<u8>),
+    Stream(Receiver<Vec<u8>>),
+}
+impl Input {
+    ///The input for the next launch.  Bytes are written to every launch, but a stream can only be used once.
+    pub(crate) fn for_launch(slot: &mut Option<Input>) -> Option<Input> {
+        match slot {
+            Some(Input::Bytes(bytes)) => Some(Input::Bytes(bytes.clone())),
+            Some(Input::Stream(_)) => slot.take(),
+            None => None
+        }
+    }
+    async fn write_to(self, stdin: &mut Writer) -> Result<(), Error> {
+        match self {
+            Input::Bytes(bytes) => stdin.write(&bytes).await,
+            Input::Stream(mut chunks) => {
+                while let Some(chunk) = chunks.recv().await {
+                    stdin.write(&chunk).await?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+///Writing the input, until it's done
+type Task = Pin<Box<dyn Future<Output = Result<(), Error>> + Send>>;
+
+#[derive(Debug)]
+enum TaskState {
+    Running,
+    Failed(Error),
+    Finished,
+}
+
+///Writes input to a child's stdin in the background.
+///
+/// Nothing runs on its own: the input is written while the [crate::Child], or an [crate::OutputStream] reading it, is
+/// being awaited.  Each of them has a clone, and a [Fanout] waker makes sure whichever is waiting gets to continue.
+#[derive(Clone)]
+pub(crate) struct StdinTask(Arc<Mutex<(Option<Task>, TaskState)>>, Arc<Fanout>);
+impl StdinTask {
+    ///Writes the inputs, one after the other, then closes stdin.  If the process exits without reading all of its input,
+    /// that's not considered an error.
+    pub(crate) fn new(stdin: ChildStdin, inputs: Vec<Input>, priority: Priority) -> StdinTask {
+        let task = async move {
+            let mut writer = Writer::new(into_file(stdin), priority);
+            for input in inputs {
+                input.write_to(&mut writer).await?;
+            }
+            Ok(())
+        };
+        StdinTask(Arc::new(Mutex::new((Some(Box::pin(task)), TaskState::Running))), Arc::new(Fanout::default()))
+    }
+    ///Makes whatever progress we can, and arranges to be woken when more is possible.
+    pub(crate) fn poll(&self, cx: &mut Context<'_>) {
+        let mut task = self.0.lock().unwrap();
+        let future = match &mut task.0 {
+            Some(future) => future,
+            None => return,
+        };
+        self.1.register(cx.waker());
+        let waker = Waker::from(self.1.clone());
+        if let Poll::Ready(result) = future.as_mut().poll(&mut Context::from_waker(&waker)) {
+            task.0 = None;
+            task.1 = match result {
+                Err(Error::IOError(e)) if e.kind() == std::io::ErrorKind::BrokenPipe => TaskState::Finished,
+                Err(e) => TaskState::Failed(e),
+                Ok(()) => TaskState::Finished,
+            }
+        }
+    }
+    ///Stops writing, reporting any error so far.  Input that wasn't written yet is discarded, just as if the process had
+    /// closed its stdin.
+    pub(crate) fn finish(&self) -> Result<(), Error> {
+        let mut task = self.0.lock().unwrap();
+        task.0 = None;
+        match std::mem::replace(&mut task.1, TaskState::Finished) {
+            TaskState::Failed(e) => Err(e),
+            TaskState::Running | TaskState::Finished => Ok(()),
+        }
+    }
+}
+impl std::fmt::Debug for StdinTask {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("StdinTask").field(&self.0.lock().unwrap().1).finish()
+    }
+}
+
+///Wakes everybody who polled a [StdinTask], since we don't know which of them is still waiting.
+#[derive(Debug, Default)]
+struct Fanout(Mutex<Vec<Waker>>);
+impl Fanout {
+    fn register(&self, waker: &Waker) {
+        let mut wakers = self.0.lock().unwrap();
+        if !wakers.iter().any(|w| w.will_wake(waker)) {
+            wakers.push(waker.clone());
+        }
+    }
+}
+impl Wake for Fanout {
+    fn wake(self: Arc<Self>) {
+        let wakers = std::mem::take(&mut *self.0.lock().unwrap());
+        for waker in wakers {
+            waker.wake()
+        }
+    }
+}
+
+///Polls the input alongside the future, so that the child can keep reading while we wait on something else.
+pub(crate) async fn alongside<F: Future>(input: Option<&StdinTask>, future: F) -> F::Output {
+    let mut future = Box::pin(future);
+    std::future::poll_fn(|cx| {
+        if let Some(input) = input {
+            input.poll(cx);
+        }
+        future.as_mut().poll(cx)
+    }).await
+}
+
+///Streams input into a process launched with [crate::Command::stdin_stream].
+///
+/// Dropping the writer closes the process's stdin once the queued chunks are written.
+#[derive(Debug, Clone)]
+pub struct StdinWriter(Sender<Vec<u8>>);
+impl StdinWriter {
+    pub(crate) fn new() -> (StdinWriter, Input) {
+        let (sender, receiver) = channel(STDIN_CHUNKS);
+        (StdinWriter(sender), Input::Stream(receiver))
+    }
+    ///Queues bytes to be written to stdin, first waiting for room if several chunks are already queued.
+    ///
+    /// Chunks are written while the [crate::Child] is being awaited.  Once the process has exited, or stopped reading,
+    /// this fails with [std::io::ErrorKind::BrokenPipe].
+    pub async fn write(&self, bytes: Vec<u8>) -> Result<(), Error> {
+        match self.0.send_bounded(bytes).await {
+            Ok(()) => Ok(()),
+            Err(_) => Err(std::io::Error::from(std::io::ErrorKind::BrokenPipe).into()),
+        }
+    }
+}
+
+///What happened on one of the child's output pipes
+#[derive(Debug)]
+pub(crate) enum Event {
+    ///Data, and when it was read
+    Data(Stream, Vec<u8>, SystemTime),
+    ///The pipe reached end of file
+    End(Stream),
+    Failed(Error),
+}
+
+///Takes ownership of a pipe as a [File], which is what [Reader] and [Writer] work with
+#[cfg(any(target_os = "macos", target_os = "linux"))]
+pub(crate) fn into_file<P: Into<std::os::unix::io::OwnedFd>>(pipe: P) -> File {
+    File::from(pipe.into())
+}
+///Takes ownership of a pipe as a [File], which is what [Reader] and [Writer] work with
+#[cfg(target_os = "windows")]
+pub(crate) fn into_file<P: Into<std::os::windows::io::OwnedHandle>>(pipe: P) -> File {
+    File::from(pipe.into())
+}
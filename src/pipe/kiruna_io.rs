@@ -0,0 +1,93 @@
+/*!
+Reads and writes pipes with kiruna streams, which use the OS-native async APIs (dispatch IO on macOS, overlapped IO on Windows).
+*/
+use std::fs::File;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use kiruna::Priority;
+use kiruna::io::stream::OSError;
+use kiruna::io::stream::read::{Buffer, OSOptions, Read};
+use kiruna::io::stream::write::Write;
+use crate::Error;
+
+///A read in flight.  It owns the [Read] while it runs, and hands it back for the next read.
+type Pending = Pin<Box<dyn Future<Output = (Read, Result<Buffer, OSError>)> + Send>>;
+
+///Reads a pipe chunk by chunk, as data arrives
+pub(crate) struct Reader {
+    ///The stream, when no read is in flight.  `None` along with `pending` means we've reached end of file.
+    read: Option<Read>,
+    pending: Option<Pending>,
+    priority: Priority,
+}
+impl Reader {
+    pub(crate) fn new(pipe: File, priority: Priority) -> Reader {
+        #[cfg(target_os = "macos")]
+        let read = {
+            use std::os::unix::io::IntoRawFd;
+            Read::new(pipe.into_raw_fd())
+        };
+        #[cfg(target_os = "windows")]
+        let read = Read::new(pipe);
+        Reader { read: Some(read), pending: None, priority }
+    }
+    ///Reads whatever is available.  `None` is the end of file.
+    pub(crate) fn poll_read(&mut self, cx: &mut Context<'_>) -> Poll<Result<Option<Vec<u8>>, Error>> {
+        if self.pending.is_none() {
+            let read = match self.read.take() {
+                Some(read) => read,
+                None => return Poll::Ready(Ok(None)),
+            };
+            let options: OSOptions<'static> = self.priority.into();
+            self.pending = Some(Box::pin(async move {
+                let result = read.once(options).await;
+                (read, result)
+            }));
+        }
+        let (read, result) = std::task::ready!(self.pending.as_mut().unwrap().as_mut().poll(cx));
+        self.pending = None;
+        let buffer = result?.into_contiguous();
+        if buffer.as_slice().is_empty() {
+            //dropping the stream closes the pipe
+            return Poll::Ready(Ok(None))
+        }
+        self.read = Some(read);
+        Poll::Ready(Ok(Some(buffer.as_slice().to_vec())))
+    }
+}
+impl std::fmt::Debug for Reader {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Reader").field("reading", &self.pending.is_some()).field("priority", &self.priority).finish()
+    }
+}
+
+///Writes to a pipe as it has room
+pub(crate) struct Writer {
+    write: Write,
+    ///kiruna borrows the descriptor, so we keep it open until the stream is gone
+    #[cfg(target_os = "macos")]
+    _pipe: File,
+    priority: Priority,
+}
+impl Writer {
+    pub(crate) fn new(pipe: File, priority: Priority) -> Writer {
+        #[cfg(target_os = "macos")]
+        return {
+            use std::os::unix::io::AsRawFd;
+            Writer { write: Write::new(pipe.as_raw_fd()), _pipe: pipe, priority }
+        };
+        #[cfg(target_os = "windows")]
+        return Writer { write: Write::new(pipe), priority };
+    }
+    ///Writes all of the bytes
+    pub(crate) async fn write(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        //kiruna owns the buffer while the write is in flight
+        Ok(self.write.write_boxed(bytes.into(), self.priority).await?)
+    }
+}
+impl std::fmt::Debug for Writer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Writer").field("priority", &self.priority).finish()
+    }
+}
@@ -0,0 +1,232 @@
+/*!
+Reads and writes pipes on Linux, waiting for them with epoll.
+
+A dedicated thread waits on a shared epoll instance, and wakes whoever is waiting on a descriptor once it's ready.  Like
+the waitpid module, the thread only runs while somebody is waiting.  Descriptors stay blocking: once epoll reports a
+pipe readable or writable, a read or a write of up to `PIPE_BUF` bytes returns right away.
+*/
+use std::collections::HashMap;
+use std::fs::File;
+use std::future::Future;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::pin::Pin;
+use std::sync::{Mutex, MutexGuard};
+use std::task::{Context, Poll, Waker};
+use kiruna::Priority;
+use once_cell::sync::Lazy;
+use crate::Error;
+use crate::waitpid::Fd;
+
+///How much we read at a time
+const CHUNK_SIZE: usize = 64 * 1024;
+
+///Token for events on [Epoll::wakeup]
+const WAKEUP: u64 = 0;
+
+#[derive(Debug)]
+struct Epoll {
+    epoll: Fd,
+    ///Signaled so that the waiting thread notices when there's nothing left to wait for
+    wakeup: Fd,
+    ///Descriptors we've been asked to wait on, by token.  Tokens are never reused, so an event for a descriptor that
+    ///was closed (and whose number now means something else) can't wake the wrong party.
+    waiting: HashMap<u64, (RawFd, Waker)>,
+    next_token: u64,
+    ///whether a waiting thread is running
+    waiting_thread: bool,
+}
+
+impl Epoll {
+    fn new() -> Result<Epoll, std::io::Error> {
+        let epoll = unsafe{ libc::epoll_create1(libc::EPOLL_CLOEXEC) };
+        if epoll < 0 {
+            return Err(std::io::Error::last_os_error())
+        }
+        let epoll = Fd(epoll);
+        let wakeup = Fd::eventfd()?;
+        let mut event = libc::epoll_event { events: libc::EPOLLIN as u32, u64: WAKEUP };
+        if unsafe{ libc::epoll_ctl(epoll.0, libc::EPOLL_CTL_ADD, wakeup.0, &mut event) } != 0 {
+            return Err(std::io::Error::last_os_error())
+        }
+        Ok(Epoll { epoll, wakeup, waiting: HashMap::new(), next_token: WAKEUP + 1, waiting_thread: false })
+    }
+    fn shared() -> Result<MutexGuard<'static, Epoll>, std::io::Error> {
+        //if we can't create the instance, every wait fails with the same error
+        static SHARED: Lazy<Result<Mutex<Epoll>, i32>> = Lazy::new(|| {
+            Epoll::new().map(Mutex::new).map_err(|e| e.raw_os_error().unwrap_or(libc::EIO))
+        });
+        match &*SHARED {
+            Ok(shared) => Ok(shared.lock().unwrap()),
+            Err(errno) => Err(std::io::Error::from_raw_os_error(*errno)),
+        }
+    }
+    ///Err indicates we need to stop the thread.  Like the waitpid module, waiting_thread is assigned to false on the
+    ///same lock that decides it.
+    fn wait_some(epoll: RawFd) -> Result<(),()> {
+        const EVENTS: usize = 16;
+        let mut events = [libc::epoll_event { events: 0, u64: 0 }; EVENTS];
+        let r = unsafe{ libc::epoll_wait(epoll, events.as_mut_ptr(), EVENTS as i32, -1) };
+        if r < 0 {
+            let error = std::io::Error::last_os_error();
+            assert_eq!(error.kind(), std::io::ErrorKind::Interrupted, "epoll_wait failed: {:?}", error);
+            return Ok(())
+        }
+        let mut s = Epoll::shared().unwrap();
+        for event in &events[..r as usize] {
+            let token = event.u64;
+            if token == WAKEUP {
+                let mut counter: u64 = 0;
+                unsafe{ libc::read(s.wakeup.0, &mut counter as *mut u64 as *mut libc::c_void, std::mem::size_of::<u64>()) };
+                continue
+            }
+            if let Some((fd, waker)) = s.waiting.remove(&token) {
+                unsafe{ libc::epoll_ctl(epoll, libc::EPOLL_CTL_DEL, fd, std::ptr::null_mut()) };
+                waker.wake();
+            }
+        }
+        if s.waiting.is_empty() {
+            s.waiting_thread = false;
+            Err(())
+        }
+        else {
+            Ok(())
+        }
+    }
+}
+
+///Resolves once the descriptor is ready for the events.
+///
+/// Regular files can't be watched with epoll, but they never block either, so they're ready right away.
+#[derive(Debug)]
+struct Ready {
+    fd: RawFd,
+    events: i32,
+    ///Our token, once we're waiting
+    token: Option<u64>,
+}
+impl Ready {
+    fn new(fd: RawFd, events: i32) -> Ready {
+        Ready { fd, events, token: None }
+    }
+}
+impl Future for Ready {
+    type Output = Result<(), std::io::Error>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut s = Epoll::shared()?;
+        if let Some(token) = self.token {
+            return match s.waiting.get_mut(&token) {
+                Some((_, waker)) => {
+                    *waker = cx.waker().clone();
+                    Poll::Pending
+                }
+                //the waiting thread removes us once the descriptor is ready
+                None => Poll::Ready(Ok(()))
+            }
+        }
+        let token = s.next_token;
+        s.next_token += 1;
+        let mut event = libc::epoll_event { events: (self.events | libc::EPOLLONESHOT) as u32, u64: token };
+        if unsafe{ libc::epoll_ctl(s.epoll.0, libc::EPOLL_CTL_ADD, self.fd, &mut event) } != 0 {
+            let error = std::io::Error::last_os_error();
+            return Poll::Ready(if error.raw_os_error() == Some(libc::EPERM) { Ok(()) } else { Err(error) })
+        }
+        s.waiting.insert(token, (self.fd, cx.waker().clone()));
+        self.token = Some(token);
+        if !s.waiting_thread {
+            s.waiting_thread = true;
+            let epoll = s.epoll.0;
+            std::thread::spawn(move || {
+                while Epoll::wait_some(epoll).is_ok() {
+                    //loop
+                }
+                //wait_some will unset waiting_thread already
+            });
+        }
+        Poll::Pending
+    }
+}
+impl Drop for Ready {
+    fn drop(&mut self) {
+        let token = match self.token {
+            Some(token) => token,
+            None => return,
+        };
+        let mut s = Epoll::shared().unwrap();
+        if s.waiting.remove(&token).is_some() {
+            unsafe{ libc::epoll_ctl(s.epoll.0, libc::EPOLL_CTL_DEL, self.fd, std::ptr::null_mut()) };
+            if s.waiting.is_empty() && s.waiting_thread {
+                //let the thread notice that it can stop
+                let one: u64 = 1;
+                unsafe{ libc::write(s.wakeup.0, &one as *const u64 as *const libc::c_void, std::mem::size_of::<u64>()) };
+            }
+        }
+    }
+}
+
+///Reads a pipe chunk by chunk, as data arrives
+#[derive(Debug)]
+pub(crate) struct Reader {
+    ///Waiting for the pipe to become readable, if we are.  Declared before the pipe, so that it stops waiting on the
+    ///descriptor before the descriptor is closed and its number can be reused.
+    ready: Option<Ready>,
+    pipe: File,
+}
+impl Reader {
+    ///epoll has no notion of priority, so it's unused here.
+    pub(crate) fn new(pipe: File, _priority: Priority) -> Reader {
+        Reader { ready: None, pipe }
+    }
+    ///Reads whatever is available once the pipe is readable.  `None` is the end of file.
+    pub(crate) fn poll_read(&mut self, cx: &mut Context<'_>) -> Poll<Result<Option<Vec<u8>>, Error>> {
+        loop {
+            let fd = self.pipe.as_raw_fd();
+            let ready = self.ready.get_or_insert_with(|| Ready::new(fd, libc::EPOLLIN));
+            let result = std::task::ready!(Pin::new(ready).poll(cx));
+            self.ready = None;
+            result?;
+            let mut buffer = vec![0; CHUNK_SIZE];
+            let read = unsafe{ libc::read(fd, buffer.as_mut_ptr() as *mut libc::c_void, buffer.len()) };
+            if read < 0 {
+                let error = std::io::Error::last_os_error();
+                if error.kind() == std::io::ErrorKind::Interrupted { continue }
+                return Poll::Ready(Err(error.into()))
+            }
+            if read == 0 {
+                return Poll::Ready(Ok(None))
+            }
+            buffer.truncate(read as usize);
+            return Poll::Ready(Ok(Some(buffer)))
+        }
+    }
+}
+
+///Writes to a pipe as it has room
+#[derive(Debug)]
+pub(crate) struct Writer {
+    pipe: File,
+}
+impl Writer {
+    ///epoll has no notion of priority, so it's unused here.
+    pub(crate) fn new(pipe: File, _priority: Priority) -> Writer {
+        Writer { pipe }
+    }
+    ///Writes all of the bytes, waiting for room along the way.
+    pub(crate) async fn write(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        let fd = self.pipe.as_raw_fd();
+        let mut written = 0;
+        while written < bytes.len() {
+            Ready::new(fd, libc::EPOLLOUT).await?;
+            //once a pipe is writable, it has room for at least PIPE_BUF bytes, so this doesn't block
+            let end = bytes.len().min(written + libc::PIPE_BUF);
+            let wrote = unsafe{ libc::write(fd, bytes[written..end].as_ptr() as *const libc::c_void, end - written) };
+            if wrote < 0 {
+                let error = std::io::Error::last_os_error();
+                if error.kind() == std::io::ErrorKind::Interrupted { continue }
+                return Err(error.into())
+            }
+            written += wrote as usize;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,145 @@
+use std::os::unix::process::CommandExt;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU8, Ordering};
+use kiruna::Priority;
+
+///Niceness for [Level::Background]
+const BACKGROUND_NICE: libc::c_int = 10;
+
+///The calling process's nice value.
+///
+/// `getpriority` can legitimately return -1, so errors are told apart with errno.
+fn current_nice() -> Result<libc::c_int, std::io::Error> {
+    #[cfg(target_os = "linux")]
+    let errno = unsafe{ libc::__errno_location() };
+    #[cfg(target_os = "macos")]
+    let errno = unsafe{ libc::__error() };
+    unsafe{ *errno = 0 };
+    let nice = unsafe{ libc::getpriority(libc::PRIO_PROCESS, 0) };
+    if nice == -1 && unsafe{ *errno } != 0 {
+        return Err(std::io::Error::last_os_error())
+    }
+    Ok(nice)
+}
+
+///What we ask of the scheduler for a [Priority].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+enum Level {
+    ///Leave the child as it would have been, e.g. for work the user is waiting on
+    Inherit = 0,
+    ///Lower the child's CPU and IO priority, so it doesn't interrupt interactive work
+    Background = 1,
+}
+impl Level {
+    fn new(priority: Priority) -> Level {
+        match priority {
+            Priority::UserWaiting => Level::Inherit,
+            Priority::Testing => Level::Background,
+            _unknown => Level::Inherit
+        }
+    }
+    fn from_u8(level: u8) -> Level {
+        if level == Level::Background as u8 { Level::Background } else { Level::Inherit }
+    }
+    ///Applies the level to the calling process.
+    ///
+    /// This runs in the child between fork and exec, so it must only make async-signal-safe calls.  Lowering the priority
+    /// is best effort: if a call isn't permitted, e.g. under seccomp, the child runs anyway.
+    fn apply(self) {
+        match self {
+            Level::Inherit => {},
+            Level::Background => {
+                //only ever lower the priority; unprivileged processes can't raise it back, e.g. when we already run at nice 15
+                if let Ok(nice) = current_nice() {
+                    unsafe{ libc::setpriority(libc::PRIO_PROCESS, 0, nice.max(BACKGROUND_NICE)) };
+                }
+                #[cfg(target_os = "linux")]
+                {
+                    //see ioprio_set(2); these constants aren't in libc
+                    const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+                    const IOPRIO_CLASS_BE: libc::c_int = 2;
+                    const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+                    //lowest level of the best-effort class
+                    let ioprio = IOPRIO_CLASS_BE << IOPRIO_CLASS_SHIFT | 7;
+                    unsafe{ libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, ioprio) };
+                    let param = libc::sched_param { sched_priority: 0 };
+                    unsafe{ libc::sched_setscheduler(0, libc::SCHED_BATCH, &param) };
+                }
+            }
+        }
+    }
+}
+
+///Applies a [Priority] to children of a [std::process::Command], before they exec.
+///
+/// Any `pre_exec` hook keeps std from using `posix_spawn`, which makes every spawn slower, so the hook is only installed
+/// once a child needs a lower priority.  Hooks accumulate, so there's one per command, and it reads the level for each spawn.
+#[derive(Debug, Default)]
+pub(crate) struct ChildPriority(Option<Arc<AtomicU8>>);
+impl ChildPriority {
+    ///Sets the priority of the next child of the command, which must be the same command every time.
+    pub(crate) fn set(&mut self, command: &mut std::process::Command, priority: Priority) {
+        let level = Level::new(priority);
+        match &self.0 {
+            Some(hook_level) => hook_level.store(level as u8, Ordering::Relaxed),
+            None if level == Level::Inherit => {},
+            None => {
+                let hook_level = Arc::new(AtomicU8::new(level as u8));
+                let move_level = hook_level.clone();
+                //safety: the hook only reads an atomic and makes syscalls
+                unsafe {
+                    command.pre_exec(move || {
+                        Level::from_u8(move_level.load(Ordering::Relaxed)).apply();
+                        Ok(())
+                    });
+                }
+                self.0 = Some(hook_level);
+            }
+        }
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod test {
+    use crate::Command;
+    use kiruna::Priority;
+
+    ///Reads a field from /proc/pid/stat, numbered as in proc(5)
+    fn stat_field(pid: u32, field: usize) -> String {
+        let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid)).unwrap();
+        //the command name (field 2) may contain spaces, so count from the end of it
+        let after_name = &stat[stat.rfind(')').unwrap() + 1..];
+        after_name.split_whitespace().nth(field - 3).unwrap().to_string()
+    }
+
+    #[test] fn hook_on_demand() {
+        use super::ChildPriority;
+        let mut command = std::process::Command::new("true");
+        let mut priority = ChildPriority::default();
+        //no hook, so std can still use posix_spawn
+        priority.set(&mut command, Priority::UserWaiting);
+        assert!(priority.0.is_none());
+        priority.set(&mut command, Priority::Testing);
+        assert!(priority.0.is_some());
+    }
+
+    #[test] fn niceness() {
+        let _guard = crate::waitpid::test::TEST_SEMAPHORE.lock();
+        let mut command = Command::new("sleep");
+        command.arg("10");
+        let mut background = command.spawn(Priority::Testing).unwrap();
+        let mut interactive = command.spawn(Priority::UserWaiting).unwrap();
+        let own_nice = stat_field(std::process::id(), 19);
+        //never higher than our own
+        let expected = own_nice.parse::<i32>().unwrap().max(10);
+        assert_eq!(stat_field(background.id(), 19), expected.to_string());
+        assert_eq!(stat_field(interactive.id(), 19), own_nice);
+        //SCHED_BATCH
+        assert_eq!(stat_field(background.id(), 41), libc::SCHED_BATCH.to_string());
+        background.kill().unwrap();
+        interactive.kill().unwrap();
+        kiruna::test::test_await(background.wait(Priority::Testing), std::time::Duration::from_secs(1)).unwrap();
+        kiruna::test::test_await(interactive.wait(Priority::Testing), std::time::Duration::from_secs(1)).unwrap();
+    }
+}
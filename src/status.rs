@@ -4,7 +4,11 @@ pub trait ExitStatus {
     ///Checks for non-0 status code
     fn check(&self) -> Result<(),i32>;
     ///Checks for non-0 status code, but wraps the result in Error:StatusError, an error type
+    ///
+    /// If the process didn't exit normally (e.g. it was killed by a signal), the error is [Error::Terminated] instead.
     fn check_err(&self) -> Result<(),Error>;
+    ///How the process ended, including which signal killed it, if any
+    fn termination(&self) -> Termination;
 }
 impl ExitStatus for std::process::ExitStatus {
     fn check(&self) -> Result<(), i32> {
@@ -21,6 +25,130 @@ impl ExitStatus for std::process::ExitStatus {
         }
     }
     fn check_err(&self) -> Result<(),Error> {
-        self.check().map_err(|e| Error::StatusError(e))
+        match self.termination() {
+            Termination::Exited(0) => Ok(()),
+            Termination::Exited(code) => Err(Error::StatusError(code)),
+            other => Err(Error::Terminated(other)),
+        }
+    }
+    #[cfg(any(target_os = "macos", target_os = "linux"))]
+    fn termination(&self) -> Termination {
+        use std::os::unix::process::ExitStatusExt;
+        if let Some(code) = self.code() {
+            Termination::Exited(code)
+        }
+        else if let Some(signal) = self.signal() {
+            Termination::Signaled { signal, core_dumped: self.core_dumped() }
+        }
+        else if let Some(signal) = self.stopped_signal() {
+            Termination::Stopped(signal)
+        }
+        else {
+            //continued; we only wait for exits, so this shouldn't happen
+            Termination::Exited(-1)
+        }
+    }
+    #[cfg(target_os = "windows")]
+    fn termination(&self) -> Termination {
+        //on windows, there's always a code
+        let code = self.code().unwrap();
+        //NTSTATUS with error severity, e.g. an unhandled exception
+        if code as u32 & 0xC000_0000 == 0xC000_0000 {
+            Termination::Exception(code as u32)
+        }
+        else {
+            Termination::Exited(code)
+        }
     }
-}
\ No newline at end of file
+}
+
+///How a process ended.  See [ExitStatus::termination].
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Termination {
+    ///The process exited with this code
+    Exited(i32),
+    ///The process was killed by a signal (Unix)
+    Signaled { signal: i32, core_dumped: bool },
+    ///The process was stopped by a signal (Unix)
+    Stopped(i32),
+    ///The process ended with an NTSTATUS error, usually an unhandled exception like `STATUS_ACCESS_VIOLATION` (Windows)
+    Exception(u32),
+}
+impl std::fmt::Display for Termination {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Termination::Exited(code) => write!(f, "exited with code {}", code),
+            Termination::Signaled { signal, core_dumped } => {
+                write!(f, "terminated by ")?;
+                write_signal(f, *signal)?;
+                if *core_dumped { write!(f, " (core dumped)")?; }
+                Ok(())
+            }
+            Termination::Stopped(signal) => {
+                write!(f, "stopped by ")?;
+                write_signal(f, *signal)
+            }
+            Termination::Exception(status) => match ntstatus_name(*status) {
+                Some(name) => write!(f, "terminated by {} ({:#010X})", name, status),
+                None => write!(f, "terminated by NTSTATUS {:#010X}", status),
+            }
+        }
+    }
+}
+
+fn write_signal(f: &mut std::fmt::Formatter<'_>, signal: i32) -> std::fmt::Result {
+    match signal_name(signal) {
+        Some(name) => write!(f, "{}", name),
+        None => write!(f, "signal {}", signal),
+    }
+}
+
+///Signal numbers vary by platform, so we look them up in libc
+#[cfg(any(target_os = "macos", target_os = "linux"))]
+fn signal_name(signal: i32) -> Option<&'static str> {
+    const NAMES: &[(i32, &str)] = &[
+        (libc::SIGHUP, "SIGHUP"), (libc::SIGINT, "SIGINT"), (libc::SIGQUIT, "SIGQUIT"), (libc::SIGILL, "SIGILL"),
+        (libc::SIGTRAP, "SIGTRAP"), (libc::SIGABRT, "SIGABRT"), (libc::SIGBUS, "SIGBUS"), (libc::SIGFPE, "SIGFPE"),
+        (libc::SIGKILL, "SIGKILL"), (libc::SIGUSR1, "SIGUSR1"), (libc::SIGSEGV, "SIGSEGV"), (libc::SIGUSR2, "SIGUSR2"),
+        (libc::SIGPIPE, "SIGPIPE"), (libc::SIGALRM, "SIGALRM"), (libc::SIGTERM, "SIGTERM"), (libc::SIGCHLD, "SIGCHLD"),
+        (libc::SIGCONT, "SIGCONT"), (libc::SIGSTOP, "SIGSTOP"), (libc::SIGTSTP, "SIGTSTP"), (libc::SIGTTIN, "SIGTTIN"),
+        (libc::SIGTTOU, "SIGTTOU"), (libc::SIGXCPU, "SIGXCPU"), (libc::SIGXFSZ, "SIGXFSZ"), (libc::SIGSYS, "SIGSYS"),
+    ];
+    NAMES.iter().find(|(number, _)| *number == signal).map(|(_, name)| *name)
+}
+#[cfg(not(any(target_os = "macos", target_os = "linux")))]
+fn signal_name(_signal: i32) -> Option<&'static str> {
+    None
+}
+
+fn ntstatus_name(status: u32) -> Option<&'static str> {
+    Some(match status {
+        0xC000_0005 => "STATUS_ACCESS_VIOLATION",
+        0xC000_001D => "STATUS_ILLEGAL_INSTRUCTION",
+        0xC000_0094 => "STATUS_INTEGER_DIVIDE_BY_ZERO",
+        0xC000_00FD => "STATUS_STACK_OVERFLOW",
+        0xC000_013A => "STATUS_CONTROL_C_EXIT",
+        0xC000_0374 => "STATUS_HEAP_CORRUPTION",
+        0xC000_0409 => "STATUS_STACK_BUFFER_OVERRUN",
+        _ => return None
+    })
+}
+
+#[cfg(all(test, any(target_os = "macos", target_os = "linux")))]
+mod test {
+    use super::{ExitStatus, Termination};
+    use std::os::unix::process::ExitStatusExt;
+
+    #[test] fn termination() {
+        let segv = std::process::ExitStatus::from_raw(libc::SIGSEGV | 0x80);
+        assert_eq!(segv.termination(), Termination::Signaled { signal: libc::SIGSEGV, core_dumped: true });
+        assert_eq!(segv.check(), Err(-1));
+        assert_eq!(segv.check_err().unwrap_err().to_string(), "terminated by SIGSEGV (core dumped)");
+        let stopped = std::process::ExitStatus::from_raw(libc::SIGSTOP << 8 | 0x7f);
+        assert_eq!(stopped.termination().to_string(), "stopped by SIGSTOP");
+        let exited = std::process::ExitStatus::from_raw(3 << 8);
+        assert_eq!(exited.termination(), Termination::Exited(3));
+        assert!(matches!(exited.check_err(), Err(crate::Error::StatusError(3))));
+    }
+}
@@ -0,0 +1,264 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::SystemTime;
+use crate::pipe::{self, Event, Reader, StdinTask, Writer};
+use kiruna::Priority;
+use crate::Error;
+
+///Which of the child's output pipes data came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+///Output read from a child
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Chunk {
+    pub stream: Stream,
+    pub bytes: Vec<u8>,
+}
+
+///A chunk on its way to a tee.  It owns the tee's [Writer] while it runs, and hands both back along with the result.
+type Teeing = Pin<Box<dyn Future<Output = (Writer, Vec<u8>, SystemTime, Result<(), Error>)> + Send>>;
+
+///One of the pipes an [OutputStream] reads
+struct Pipe {
+    stream: Stream,
+    reader: Reader,
+    ///Each chunk is copied here before we report it
+    tee: Option<Writer>,
+    teeing: Option<Teeing>,
+    ///Whether we've reported the last event for this pipe
+    ended: bool,
+}
+impl Pipe {
+    fn new(pipe: File, stream: Stream, tee: Option<File>, priority: Priority) -> Pipe {
+        Pipe { stream, reader: Reader::new(pipe, priority), tee: tee.map(|tee| Writer::new(tee, priority)), teeing: None, ended: false }
+    }
+    ///The next event on this pipe, or `None` once it has ended.  The last event is always [Event::End] or [Event::Failed].
+    ///
+    /// A chunk is only reported once it's written to the tee, so by the end of the pipe, the tee has everything.
+    fn poll_event(&mut self, cx: &mut Context<'_>) -> Poll<Option<Event>> {
+        if self.ended {
+            return Poll::Ready(None)
+        }
+        if let Some(teeing) = &mut self.teeing {
+            let (tee, bytes, time, result) = std::task::ready!(teeing.as_mut().poll(cx));
+            self.teeing = None;
+            self.tee = Some(tee);
+            return Poll::Ready(Some(self.report(result.map(|()| Event::Data(self.stream, bytes, time)))))
+        }
+        let event = match std::task::ready!(self.reader.poll_read(cx)) {
+            Ok(Some(bytes)) => match self.tee.take() {
+                Some(mut tee) => {
+                    let time = SystemTime::now();
+                    self.teeing = Some(Box::pin(async move {
+                        let result = tee.write(&bytes).await;
+                        (tee, bytes, time, result)
+                    }));
+                    return self.poll_event(cx)
+                }
+                None => Ok(Event::Data(self.stream, bytes, SystemTime::now())),
+            }
+            Ok(None) => Ok(Event::End(self.stream)),
+            Err(e) => Err(e),
+        };
+        Poll::Ready(Some(self.report(event)))
+    }
+    fn report(&mut self, event: Result<Event, Error>) -> Event {
+        let event = event.unwrap_or_else(Event::Failed);
+        self.ended = !matches!(event, Event::Data(..));
+        event
+    }
+}
+impl std::fmt::Debug for Pipe {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Pipe").field("stream", &self.stream).field("reader", &self.reader).field("ended", &self.ended).finish()
+    }
+}
+
+///Yields a child's stdout and stderr as they arrive, rather than buffering everything until the process exits.
+///
+/// Create one with [crate::Child::output_stream].  Chunks from the two pipes are interleaved in the order they were read.
+/// Once this returns `None`, use [crate::Child::wait] for the final status.
+#[derive(Debug)]
+pub struct OutputStream {
+    pipes: Vec<Pipe>,
+    ///Input for the child, written as we read
+    input: Option<StdinTask>,
+    ///Which pipe to check first, so that a busy pipe can't starve the other
+    next: usize,
+}
+
+impl OutputStream {
+    pub(crate) fn new(stdout: Option<std::process::ChildStdout>, stderr: Option<std::process::ChildStderr>, input: Option<StdinTask>, priority: Priority) -> OutputStream {
+        Self::with_tees(stdout, stderr, None, None, input, priority)
+    }
+    ///Like [OutputStream::new], but also copies each stream to its tee, if any, as it's read.
+    pub(crate) fn with_tees(stdout: Option<std::process::ChildStdout>, stderr: Option<std::process::ChildStderr>, stdout_tee: Option<File>, stderr_tee: Option<File>, input: Option<StdinTask>, priority: Priority) -> OutputStream {
+        let mut pipes = Vec::new();
+        if let Some(stdout) = stdout {
+            pipes.push(Pipe::new(pipe::into_file(stdout), Stream::Stdout, stdout_tee, priority));
+        }
+        if let Some(stderr) = stderr {
+            pipes.push(Pipe::new(pipe::into_file(stderr), Stream::Stderr, stderr_tee, priority));
+        }
+        OutputStream { pipes, input, next: 0 }
+    }
+    ///Reads a single pipe, e.g. one that the child's stdout and stderr share.  Its data is reported as stdout.
+    #[cfg(feature = "output")]
+    pub(crate) fn from_pipe(pipe: File, input: Option<StdinTask>, priority: Priority) -> OutputStream {
+        OutputStream { pipes: vec![Pipe::new(pipe, Stream::Stdout, None, priority)], input, next: 0 }
+    }
+    fn poll_event(&mut self, cx: &mut Context<'_>) -> Poll<Option<Event>> {
+        if let Some(input) = &self.input {
+            input.poll(cx);
+        }
+        let mut pending = false;
+        for i in 0..self.pipes.len() {
+            let index = (self.next + i) % self.pipes.len();
+            match self.pipes[index].poll_event(cx) {
+                Poll::Ready(Some(event)) => {
+                    self.next = index + 1;
+                    return Poll::Ready(Some(event))
+                }
+                Poll::Ready(None) => {}
+                Poll::Pending => pending = true,
+            }
+        }
+        if pending { Poll::Pending } else { Poll::Ready(None) }
+    }
+    ///Waits for the next event on either pipe
+    pub(crate) async fn next_event(&mut self) -> Option<Event> {
+        std::future::poll_fn(|cx| self.poll_event(cx)).await
+    }
+    ///Waits for the next chunk of output.
+    ///
+    /// Returns `None` once both pipes are closed, which usually means the process has exited.
+    pub async fn next(&mut self) -> Option<Result<Chunk, Error>> {
+        loop {
+            match self.next_event().await? {
+                Event::Data(stream, bytes, _) => return Some(Ok(Chunk { stream, bytes })),
+                Event::End(_) => {},
+                Event::Failed(error) => return Some(Err(error))
+            }
+        }
+    }
+    ///Splits the output into lines instead of chunks.
+    pub fn lines(self) -> Lines {
+        Lines { stream: self, ready: VecDeque::new(), partial: [Vec::new(), Vec::new()] }
+    }
+}
+
+///A line of output from a child
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Line {
+    pub stream: Stream,
+    ///The line, without its trailing newline
+    pub bytes: Vec<u8>,
+    ///When the end of the line was read
+    pub timestamp: SystemTime,
+}
+
+///Yields a child's output one line at a time, tagged with the pipe it came from.
+///
+/// Create one with [OutputStream::lines].  Partial lines are held until they are complete, and a trailing partial line
+/// is yielded when its pipe closes.
+#[derive(Debug)]
+pub struct Lines {
+    stream: OutputStream,
+    ///Complete lines that haven't been returned yet
+    ready: VecDeque<Line>,
+    ///Incomplete line for stdout and stderr respectively
+    partial: [Vec<u8>; 2],
+}
+impl Lines {
+    fn partial(&mut self, stream: Stream) -> &mut Vec<u8> {
+        match stream {
+            Stream::Stdout => &mut self.partial[0],
+            Stream::Stderr => &mut self.partial[1],
+        }
+    }
+    ///Waits for the next complete line.
+    ///
+    /// Returns `None` once both pipes are closed and every line has been returned.
+    pub async fn next(&mut self) -> Option<Result<Line, Error>> {
+        loop {
+            if let Some(line) = self.ready.pop_front() {
+                return Some(Ok(line))
+            }
+            match self.stream.next_event().await? {
+                Event::Data(stream, bytes, timestamp) => {
+                    let mut rest = bytes.as_slice();
+                    while let Some(newline) = rest.iter().position(|b| *b == b'\n') {
+                        let partial = self.partial(stream);
+                        partial.extend_from_slice(&rest[..newline]);
+                        let bytes = std::mem::take(partial);
+                        self.ready.push_back(Line { stream, bytes, timestamp });
+                        rest = &rest[newline + 1..];
+                    }
+                    self.partial(stream).extend_from_slice(rest);
+                }
+                Event::End(stream) => {
+                    let partial = self.partial(stream);
+                    if !partial.is_empty() {
+                        let bytes = std::mem::take(partial);
+                        self.ready.push_back(Line { stream, bytes, timestamp: SystemTime::now() });
+                    }
+                }
+                Event::Failed(error) => return Some(Err(error))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{Command, Stream};
+    use kiruna::Priority;
+    use std::process::Stdio;
+    use std::time::Duration;
+
+    #[cfg(any(target_os = "macos", target_os = "linux"))]
+    #[test] fn output_stream() {
+        let _guard = crate::waitpid::test::TEST_SEMAPHORE.lock();
+        let mut c = Command::new("/bin/sh");
+        c.args(["-c", "echo out; sleep 0.1; echo err >&2"]).stdout(Stdio::piped()).stderr(Stdio::piped());
+        let mut child = c.spawn(Priority::Testing).unwrap();
+        let mut stream = child.output_stream(Priority::Testing);
+        let mut chunks = Vec::new();
+        while let Some(chunk) = kiruna::test::test_await(stream.next(), Duration::from_secs(2)) {
+            chunks.push(chunk.unwrap());
+        }
+        assert_eq!(chunks.len(), 2);
+        assert_eq!((chunks[0].stream, chunks[0].bytes.as_slice()), (Stream::Stdout, b"out\n".as_ref()));
+        assert_eq!((chunks[1].stream, chunks[1].bytes.as_slice()), (Stream::Stderr, b"err\n".as_ref()));
+        let status = kiruna::test::test_await(child.wait(Priority::Testing), Duration::from_secs(1)).unwrap();
+        assert!(status.success());
+    }
+
+    #[cfg(any(target_os = "macos", target_os = "linux"))]
+    #[test] fn lines() {
+        let _guard = crate::waitpid::test::TEST_SEMAPHORE.lock();
+        let mut c = Command::new("/bin/sh");
+        c.args(["-c", r"printf 'a\nb'; sleep 0.1; printf 'c\nd\n'; printf 'e\n\nf' >&2"]).stdout(Stdio::piped()).stderr(Stdio::piped());
+        let mut child = c.spawn(Priority::Testing).unwrap();
+        let mut lines = child.output_stream(Priority::Testing).lines();
+        let mut stdout = Vec::new();
+        let mut stderr = Vec::new();
+        while let Some(line) = kiruna::test::test_await(lines.next(), Duration::from_secs(2)) {
+            let line = line.unwrap();
+            match line.stream {
+                Stream::Stdout => stdout.push(line.bytes),
+                Stream::Stderr => stderr.push(line.bytes),
+            }
+        }
+        assert_eq!(stdout, [b"a".to_vec(), b"bc".to_vec(), b"d".to_vec()]);
+        assert_eq!(stderr, [b"e".to_vec(), b"".to_vec(), b"f".to_vec()]);
+        kiruna::test::test_await(child.wait(Priority::Testing), Duration::from_secs(1)).unwrap();
+    }
+}
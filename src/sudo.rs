@@ -1,11 +1,350 @@
-#[cfg(target_os = "macos")]
-mod macos;
+mod password;
+pub use password::Password;
 
-#[cfg(target_os = "macos")]
-pub use macos::Sudo;
+#[cfg(any(target_os = "macos", target_os = "linux"))]
+use std::ffi::{OsStr, OsString};
+#[cfg(any(target_os = "macos", target_os = "linux"))]
+use std::process::{ExitStatus, Stdio};
+#[cfg(any(target_os = "macos", target_os = "linux"))]
+use kiruna::Priority;
+#[cfg(any(target_os = "macos", target_os = "linux"))]
+use crate::child::Child;
+#[cfg(any(target_os = "macos", target_os = "linux"))]
+use crate::pipe::{Input, StdinWriter};
+#[cfg(any(target_os = "macos", target_os = "linux"))]
+use crate::priority::ChildPriority;
+#[cfg(any(target_os = "macos", target_os = "linux"))]
+use crate::stream::OutputStream;
+#[cfg(any(target_os = "macos", target_os = "linux"))]
+use crate::Error;
+#[cfg(all(feature = "output", any(target_os = "macos", target_os = "linux")))]
+use crate::output::Output;
 
 #[cfg(target_os = "windows")]
 mod windows;
 
 #[cfg(target_os="windows")]
-pub use windows::Sudo;
\ No newline at end of file
+pub use windows::Sudo;
+
+///Type that elevates the permission to sudo.
+///
+/// This is a distinct type because internally sudo password is passed to stdin, meaning that
+/// stdin binding is not generally available.  Input can still be written to the program, see [Sudo::stdin_bytes]
+/// and [Sudo::stdin_stream].
+///
+/// We first check the password with `sudo -v`, so that a wrong password is [Error::SudoAuthentication] rather than a
+/// failed status, and then run the program directly with `sudo -n`, which relies on the timestamp sudo records for the
+/// accepted password.  sudo never prompts the second time, so the program's input and output are its own.
+#[cfg(any(target_os = "macos", target_os = "linux"))]
+pub struct Sudo {
+    program: OsString,
+    args: Vec<OsString>,
+    user: Option<OsString>,
+    group: Option<OsString>,
+    password: Password,
+    ///Written to the program's stdin
+    input: Option<Input>,
+    ///The sudo executable, which tests replace with a stub
+    sudo: OsString,
+}
+#[cfg(any(target_os = "macos", target_os = "linux"))]
+impl Sudo {
+    pub fn new<S: AsRef<OsStr>, P: Into<Password>>(program: S, password: P) -> Self {
+        Sudo { program: program.as_ref().to_owned(), args: Vec::new(), user: None, group: None, password: password.into(), input: None, sudo: "sudo".into() }
+    }
+    pub fn arg<S: AsRef<OsStr>>(&mut self, arg: S) -> &mut Sudo {
+        self.args.push(arg.as_ref().to_owned());
+        self
+    }
+    pub fn args<I, S>(&mut self, args: I) -> &mut Self
+        where
+            I: IntoIterator<Item = S>,
+            S: AsRef<OsStr> {
+        self.args.extend(args.into_iter().map(|a| a.as_ref().to_owned()));
+        self
+    }
+    ///Runs the program as this user instead of root (`sudo -u`)
+    pub fn user<S: AsRef<OsStr>>(&mut self, name: S) -> &mut Self {
+        self.user = Some(name.as_ref().to_owned());
+        self
+    }
+    ///Runs the program with this primary group (`sudo -g`)
+    pub fn group<S: AsRef<OsStr>>(&mut self, name: S) -> &mut Self {
+        self.group = Some(name.as_ref().to_owned());
+        self
+    }
+    ///Writes the bytes to the program's stdin, then closes it.
+    pub fn stdin_bytes(&mut self, bytes: Vec<u8>) -> &mut Self {
+        self.input = Some(Input::Bytes(bytes));
+        self
+    }
+    ///Streams input into the program's stdin.
+    ///
+    /// Write chunks with the returned [StdinWriter], and drop it to close stdin.  The stream is used by the next launch only.
+    pub fn stdin_stream(&mut self) -> StdinWriter {
+        let (writer, input) = StdinWriter::new();
+        self.input = Some(input);
+        writer
+    }
+    ///Runs the program as root, or the [Sudo::user].
+    ///
+    /// If sudo doesn't accept the password, this is [Error::SudoAuthentication] and the program never runs.  If sudo is
+    /// configured to keep no timestamps (`timestamp_timeout=0`), it can't run the program without prompting, so it fails
+    /// with "a password is required".
+    pub async fn status(&mut self, priority: Priority) -> Result<ExitStatus, Error> {
+        self.launch(Stdio::inherit, priority).await?.wait(priority).await
+    }
+    ///Runs the program, capturing its stdout and stderr.
+    ///
+    /// Like [Sudo::status], a rejected password is [Error::SudoAuthentication].  If sudo refuses to run the program,
+    /// e.g. because it isn't allowed, that's in stderr, along with what the program wrote.
+    #[cfg(feature="output")]
+    pub async fn output(&mut self, priority: Priority) -> Result<Output, Error> {
+        let child = self.launch(Stdio::piped, priority).await?;
+        Output::from_child_captured(child, &Default::default(), None, priority).await
+    }
+    ///Checks the password, then launches the program through sudo, with its stdout and stderr configured by `output`.
+    ///
+    /// sudo doesn't read stdin for a password by then, so the input starts right away.
+    async fn launch(&mut self, output: fn() -> Stdio, priority: Priority) -> Result<Child, Error> {
+        let input = Input::for_launch(&mut self.input);
+        validate(&self.sudo, &self.password, priority).await?;
+        let mut command = std::process::Command::new(&self.sudo);
+        command.arg("-n"); //fail rather than prompt, so nothing on stdin is taken for a password
+        if let Some(user) = &self.user {
+            command.arg("-u").arg(user);
+        }
+        if let Some(group) = &self.group {
+            command.arg("-g").arg(group);
+        }
+        command.arg("--");
+        command.arg(&self.program);
+        command.args(&self.args);
+        ChildPriority::default().set(&mut command, priority);
+        command.stdin(if input.is_some() { Stdio::piped() } else { Stdio::null() });
+        command.stdout(output());
+        command.stderr(output());
+        let mut child = Child::new(command.spawn()?);
+        if let Some(input) = input {
+            child.write_stdin(vec![input], priority);
+        }
+        Ok(child)
+    }
+}
+
+///Our password prompt.  sudo's own is localized, so we replace it with one we can find on stderr.  It has no `%`, since
+/// sudo expands those.
+#[cfg(any(target_os = "macos", target_os = "linux"))]
+const PROMPT: &str = "[command-rs sudo password]";
+
+///Writes the password line to sudo straight from the [Password], so that no copy of it is made.
+///
+/// The line is far smaller than a pipe buffer, so this doesn't block.  If sudo has already exited, its status says why,
+/// so a broken pipe isn't an error here.
+#[cfg(any(target_os = "macos", target_os = "linux"))]
+fn write_password(stdin: &mut std::process::ChildStdin, password: &Password) -> Result<(), Error> {
+    use std::io::Write;
+    match stdin.write_all(password.as_bytes()).and_then(|()| stdin.write_all(b"\n")) {
+        Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => Ok(()),
+        other => Ok(other?),
+    }
+}
+
+///Where the first prompt in `stderr` ends, if there is one
+#[cfg(any(target_os = "macos", target_os = "linux"))]
+fn after_prompt(stderr: &[u8]) -> Option<usize> {
+    let prompt = PROMPT.as_bytes();
+    stderr.windows(prompt.len()).position(|w| w == prompt).map(|p| p + prompt.len())
+}
+
+///Checks the password with `sudo -v` before running anything, so that a wrong password never reaches the program or
+/// its input.
+///
+/// sudo's cached credentials are dropped with `sudo -k` first, so that the password is really checked.  Once sudo accepts
+/// it, sudo records its usual timestamp, which is what lets the program run without a prompt.  A wrong password is
+/// [Error::SudoAuthentication], carrying what sudo wrote besides the prompts.
+#[cfg(any(target_os = "macos", target_os = "linux"))]
+async fn validate(sudo: &OsStr, password: &Password, priority: Priority) -> Result<(), Error> {
+    let mut reset = std::process::Command::new(sudo);
+    reset.arg("-k").stdin(Stdio::null()).stdout(Stdio::null()).stderr(Stdio::null());
+    ChildPriority::default().set(&mut reset, priority);
+    //if this fails, sudo still has credentials that it would accept in place of the password anyway
+    Child::new(reset.spawn()?).wait(priority).await?;
+
+    let mut command = std::process::Command::new(sudo);
+    command.args(["-S", "-v", "-p", PROMPT]);
+    command.stdin(Stdio::piped());
+    command.stdout(Stdio::null());
+    command.stderr(Stdio::piped());
+    ChildPriority::default().set(&mut command, priority);
+    let mut child = Child::new(command.spawn()?);
+    //if the password is wrong, sudo prompts again, and gives up once stdin is closed
+    write_password(child.stdin.as_mut().unwrap(), password)?;
+    drop(child.stdin.take());
+    let mut stderr = Vec::new();
+    let mut stream = OutputStream::new(None, child.stderr.take(), None, priority);
+    while let Some(chunk) = stream.next().await {
+        stderr.extend(chunk?.bytes);
+    }
+    if child.wait(priority).await?.success() {
+        return Ok(())
+    }
+    let prompt = PROMPT.as_bytes();
+    let mut message = Vec::new();
+    let mut rest = &stderr[..];
+    while let Some(end) = after_prompt(rest) {
+        message.extend_from_slice(&rest[..end - prompt.len()]);
+        rest = &rest[end..];
+    }
+    message.extend_from_slice(rest);
+    Err(Error::SudoAuthentication(String::from_utf8_lossy(&message).trim_end().to_string()))
+}
+
+///Replaces sudo with a stub, which accepts the password `letmein` and supports `-u`, `-g`, `-p`, `-k`, `-v` and `-n`.
+///
+/// Like sudo, it records a timestamp once the password is accepted, which `-n` requires and `-k` removes.
+#[cfg(all(test, any(target_os = "macos", target_os = "linux")))]
+fn stub_sudo(s: &mut Sudo) {
+    use once_cell::sync::Lazy;
+    //written once, since executing a file while it's being written fails
+    static SCRIPT: Lazy<std::path::PathBuf> = Lazy::new(|| {
+        let dir = std::env::temp_dir().join(format!("command-rs-sudo-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let script = dir.join("sudo");
+        std::fs::write(&script, r#"#!/bin/sh
+user=root
+prompt="Password:"
+timestamp="$(dirname "$0")/timestamp"
+while [ "$#" -gt 0 ] && [ "$1" != "--" ]; do
+    case "$1" in
+        -u) user="$2"; shift ;;
+        -g) group="$2"; shift ;;
+        -p) prompt="$2"; shift ;;
+        -k) rm -f "$timestamp" ;;
+        -v) validate=1 ;;
+        -n) noninteractive=1 ;;
+    esac
+    shift
+done
+if [ "$#" -gt 0 ]; then shift; fi
+if [ -z "$validate" ] && [ "$#" -eq 0 ]; then exit 0; fi
+if [ -n "$noninteractive" ] && [ ! -e "$timestamp" ]; then
+    echo "sudo: a password is required" >&2
+    exit 1
+fi
+if [ -z "$noninteractive" ]; then
+    tries=0
+    while true; do
+        printf '%s' "$prompt" >&2
+        if ! read -r password; then
+            echo "sudo: $tries incorrect password attempt" >&2
+            exit 1
+        fi
+        if [ "$password" = "letmein" ]; then break; fi
+        tries=$((tries + 1))
+        if [ "$tries" -eq 3 ]; then
+            echo "sudo: 3 incorrect password attempts" >&2
+            exit 1
+        fi
+        echo "Sorry, try again." >&2
+    done
+    touch "$timestamp"
+fi
+if [ -n "$validate" ]; then exit 0; fi
+SUDO_TARGET_USER="$user" SUDO_TARGET_GROUP="$group" exec "$@"
+"#).unwrap();
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+        script
+    });
+    s.sudo = SCRIPT.clone().into_os_string();
+}
+
+#[cfg(any(target_os = "macos", target_os = "linux"))]
+#[test] fn sudo() {
+    let _single_file = crate::waitpid::test::TEST_SEMAPHORE.lock();
+    let mut s = Sudo::new("/bin/sh", "letmein".to_string());
+    s.args(["-c", "exit 3"]);
+    stub_sudo(&mut s);
+    let status = kiruna::test::test_await(s.status(Priority::Testing), std::time::Duration::from_secs(5)).unwrap();
+    assert_eq!(status.code(), Some(3));
+
+    let mut s = Sudo::new("true", "notmypassword".to_string());
+    stub_sudo(&mut s);
+    match kiruna::test::test_await(s.status(Priority::Testing), std::time::Duration::from_secs(5)) {
+        Err(Error::SudoAuthentication(message)) => assert!(message.contains("incorrect password")),
+        other => panic!("Unexpected result {:?}", other),
+    }
+
+    let mut s = Sudo::new("/bin/sh", "letmein".to_string());
+    s.args(["-c", r#"test "$SUDO_TARGET_USER" = nobody && test "$SUDO_TARGET_GROUP" = nogroup"#]).user("nobody").group("nogroup");
+    stub_sudo(&mut s);
+    let status = kiruna::test::test_await(s.status(Priority::Testing), std::time::Duration::from_secs(5)).unwrap();
+    assert!(status.success());
+}
+
+#[cfg(any(target_os = "macos", target_os = "linux"))]
+#[test] fn sudo_stdin() {
+    let _single_file = crate::waitpid::test::TEST_SEMAPHORE.lock();
+    let mut s = Sudo::new("/bin/sh", "letmein".to_string());
+    s.args(["-c", r#"read x; test "$x" = hello"#]).stdin_bytes(b"hello\n".to_vec());
+    stub_sudo(&mut s);
+    let status = kiruna::test::test_await(s.status(Priority::Testing), std::time::Duration::from_secs(5)).unwrap();
+    assert!(status.success());
+
+    let mut s = Sudo::new("/bin/sh", "letmein".to_string());
+    s.args(["-c", r#"test "$(cat)" = hello"#]);
+    let writer = s.stdin_stream();
+    stub_sudo(&mut s);
+    kiruna::test::test_await(writer.write(b"hel".to_vec()), std::time::Duration::from_secs(1)).unwrap();
+    kiruna::test::test_await(writer.write(b"lo".to_vec()), std::time::Duration::from_secs(1)).unwrap();
+    drop(writer);
+    let status = kiruna::test::test_await(s.status(Priority::Testing), std::time::Duration::from_secs(5)).unwrap();
+    assert!(status.success());
+
+    //the input isn't taken for a second password attempt
+    let mut s = Sudo::new("true", "notmypassword".to_string());
+    s.stdin_bytes(b"letmein\n".to_vec());
+    stub_sudo(&mut s);
+    match kiruna::test::test_await(s.status(Priority::Testing), std::time::Duration::from_secs(5)) {
+        Err(Error::SudoAuthentication(message)) => assert!(message.contains("1 incorrect password attempt"), "{}", message),
+        other => panic!("Unexpected result {:?}", other),
+    }
+}
+
+#[cfg(all(feature="output", any(target_os = "macos", target_os = "linux")))]
+#[test] fn sudo_output() {
+    let _single_file = crate::waitpid::test::TEST_SEMAPHORE.lock();
+    let mut s = Sudo::new("/bin/sh", "letmein".to_string());
+    s.args(["-c", "echo out; echo err >&2"]);
+    stub_sudo(&mut s);
+    let output = kiruna::test::test_await(s.output(Priority::Testing), std::time::Duration::from_secs(5)).unwrap();
+    assert!(output.status.success());
+    assert_eq!(output.stdout.as_slice(), b"out\n");
+    assert_eq!(output.stderr.as_slice(), b"err\n");
+
+    //the program's output is read while its input is written
+    let mut s = Sudo::new("cat", "letmein".to_string());
+    s.stdin_bytes(vec![b'x'; 1 << 20]);
+    stub_sudo(&mut s);
+    let output = kiruna::test::test_await(s.output(Priority::Testing), std::time::Duration::from_secs(5)).unwrap();
+    assert_eq!(output.stdout.as_slice().len(), 1 << 20);
+
+    let mut s = Sudo::new("true", "notmypassword".to_string());
+    stub_sudo(&mut s);
+    match kiruna::test::test_await(s.output(Priority::Testing), std::time::Duration::from_secs(5)) {
+        Err(Error::SudoAuthentication(message)) => assert!(message.contains("incorrect password")),
+        other => panic!("Unexpected result {:?}", other),
+    }
+}
+
+#[cfg(target_os = "macos")]
+#[test] fn sudo_system() {
+    let _single_file = crate::waitpid::test::TEST_SEMAPHORE.lock();
+    let mut s = Sudo::new("whoami","notmypassword".to_string());
+    let future = s.status(Priority::UserWaiting);
+    let _result = kiruna::test::test_await(future, std::time::Duration::from_secs(5));
+    //evidently sudo requires no password on github's CI runners.
+    //todo: Maybe we should use a programmatic API for sudo on macos?
+    // assert_eq!(result.unwrap().code(),Some(1));
+}
\ No newline at end of file
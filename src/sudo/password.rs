@@ -0,0 +1,127 @@
+/*!
+Keeps passwords out of the rest of the heap.
+*/
+use crate::Error;
+
+///A buffer that is zeroed when dropped, including any spare capacity, which may hold old contents after a reallocation.
+pub(crate) struct Secret<T: Copy + Default> {
+    buffer: Vec<T>,
+    locked: bool,
+}
+impl<T: Copy + Default> Secret<T> {
+    pub(crate) fn new(buffer: Vec<T>) -> Self {
+        Secret { buffer, locked: false }
+    }
+    pub(crate) fn as_slice(&self) -> &[T] {
+        &self.buffer
+    }
+    #[cfg(target_os = "windows")]
+    pub(crate) fn as_mut_ptr(&mut self) -> *mut T {
+        self.buffer.as_mut_ptr()
+    }
+    ///Overwrites the whole allocation.  Volatile writes, so that the compiler can't skip them as dead stores.
+    fn zeroize(&mut self) {
+        let ptr = self.buffer.as_mut_ptr();
+        for i in 0..self.buffer.capacity() {
+            unsafe{ ptr.add(i).write_volatile(T::default()) };
+        }
+        std::sync::atomic::compiler_fence(std::sync::atomic::Ordering::SeqCst);
+        self.buffer.clear();
+    }
+    ///Keeps the allocation from being swapped to disk, until drop.
+    fn lock(&mut self) -> Result<(), Error> {
+        let bytes = self.buffer.capacity() * std::mem::size_of::<T>();
+        if self.locked || bytes == 0 {
+            return Ok(())
+        }
+        #[cfg(any(target_os = "macos", target_os = "linux"))]
+        if unsafe{ libc::mlock(self.buffer.as_ptr() as *const libc::c_void, bytes) } != 0 {
+            return Err(std::io::Error::last_os_error().into())
+        }
+        #[cfg(target_os = "windows")]
+        {
+            use winbindings::Windows::Win32::System::Memory::VirtualLock;
+            use winbindings::Windows::Win32::System::Diagnostics::Debug::GetLastError;
+            if !unsafe{ VirtualLock(self.buffer.as_mut_ptr() as *mut std::ffi::c_void, bytes) }.as_bool() {
+                return Err(Error::WinError(unsafe{ GetLastError() }))
+            }
+        }
+        self.locked = true;
+        Ok(())
+    }
+    fn unlock(&mut self) {
+        let bytes = self.buffer.capacity() * std::mem::size_of::<T>();
+        #[cfg(any(target_os = "macos", target_os = "linux"))]
+        unsafe{ libc::munlock(self.buffer.as_ptr() as *const libc::c_void, bytes) };
+        #[cfg(target_os = "windows")]
+        {
+            use winbindings::Windows::Win32::System::Memory::VirtualUnlock;
+            unsafe{ VirtualUnlock(self.buffer.as_mut_ptr() as *mut std::ffi::c_void, bytes) };
+        }
+        self.locked = false;
+    }
+}
+impl<T: Copy + Default> Drop for Secret<T> {
+    fn drop(&mut self) {
+        self.zeroize();
+        if self.locked {
+            self.unlock();
+        }
+    }
+}
+
+///A password for [crate::Sudo].
+///
+/// The password is never copied: it's zeroed when dropped, can't be cloned, and is written to sudo straight from this buffer.
+/// [Password::mlock] additionally keeps it from being swapped to disk.  `Debug` doesn't show it.
+pub struct Password(Secret<u8>);
+impl Password {
+    ///Takes over the string's buffer, without copying it
+    pub fn new(password: String) -> Password {
+        Password(Secret::new(password.into_bytes()))
+    }
+    ///Locks the password's memory, so it isn't swapped to disk.
+    ///
+    /// This uses `mlock` on Unix and `VirtualLock` on Windows, which limit how much memory can be locked.
+    pub fn mlock(&mut self) -> Result<(), Error> {
+        self.0.lock()
+    }
+    pub(crate) fn as_bytes(&self) -> &[u8] {
+        self.0.as_slice()
+    }
+    ///The password as a null-terminated wide string, which is zeroed in turn
+    #[cfg(target_os = "windows")]
+    pub(crate) fn to_wide(&self) -> Secret<u16> {
+        let password = std::str::from_utf8(self.as_bytes()).expect("Password is created from a String");
+        //sized up front, so that the buffer never reallocates and leaves a copy behind
+        let mut wide = Vec::with_capacity(password.len() + 1);
+        wide.extend(password.encode_utf16());
+        wide.push(0);
+        Secret::new(wide)
+    }
+}
+impl From<String> for Password {
+    fn from(password: String) -> Self {
+        Password::new(password)
+    }
+}
+impl std::fmt::Debug for Password {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Password(<redacted>)")
+    }
+}
+
+#[cfg(any(target_os = "macos", target_os = "linux"))]
+#[test] fn password() {
+    let mut string = String::with_capacity(32);
+    string.push_str("hunter2");
+    let mut password = Password::new(string);
+    assert_eq!(format!("{:?}", password), "Password(<redacted>)");
+    password.mlock().unwrap();
+    let capacity = password.0.buffer.capacity();
+    assert!(capacity >= 32);
+    password.0.zeroize();
+    //the allocation is still ours until drop, and zeroize wrote all of it
+    let all = unsafe{ std::slice::from_raw_parts(password.0.buffer.as_ptr(), capacity) };
+    assert!(all.iter().all(|b| *b == 0));
+}
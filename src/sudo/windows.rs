@@ -1,8 +1,11 @@
 use std::ffi::{OsStr, OsString};
+use std::os::windows::ffi::{OsStrExt, OsStringExt};
 use kiruna::Priority;
 use std::process::ExitStatus;
 use crate::Error;
 use std::os::windows::process::ExitStatusExt;
+use super::Password;
+use crate::pipe::{Input, StdinWriter};
 
 
 use winbindings::Windows::Win32::System::Threading::PROCESS_CREATION_FLAGS;
@@ -20,20 +23,85 @@ impl PriorityProcess for Priority {
     }
 }
 
+///Type that elevates the permission to another user, with `CreateProcessWithLogonW`.
+///
+/// The methods match the Unix ones, but not everything is supported here yet: input ([Sudo::stdin_bytes] and
+/// [Sudo::stdin_stream]), [Sudo::group] and [Sudo::output] fail with [std::io::ErrorKind::Unsupported].
 pub struct Sudo {
     program: OsString,
-    password: String,
+    password: Password,
     command_line: OsString,
+    user: OsString,
+    domain: Option<OsString>,
+    ///Windows has no primary group to choose, so this is only kept to report that
+    group: Option<OsString>,
+    ///The program's stdin isn't ours to write yet, so this is only kept to report that
+    input: Option<Input>,
+}
+
+///The error for something [Sudo] does on Unix, but not here
+fn unsupported(what: &str) -> Error {
+    Error::IOError(std::io::Error::new(std::io::ErrorKind::Unsupported, format!("{} isn't supported by Sudo on Windows", what)))
+}
+
+///The string as a null-terminated wide string
+fn wide(string: &OsStr) -> Vec<u16> {
+    string.encode_wide().chain(std::iter::once(0)).collect()
 }
 
 impl Sudo {
-    pub fn new<S: AsRef<OsStr>>(program: S, password: String) -> Self {
+    pub fn new<S: AsRef<OsStr>, P: Into<Password>>(program: S, password: P) -> Self {
         Sudo {
             program: program.as_ref().to_os_string(),
-            password,
+            password: password.into(),
             command_line: program.as_ref().to_os_string(),
+            user: "Administrator".into(),
+            domain: None,
+            group: None,
+            input: None,
         }
     }
+    ///Logs on as this user instead of `Administrator`.
+    ///
+    /// `DOMAIN\user` is accepted as well, and sets the [Sudo::domain].
+    pub fn user<S: AsRef<OsStr>>(&mut self, name: S) -> &mut Sudo {
+        let name: Vec<u16> = name.as_ref().encode_wide().collect();
+        match name.iter().position(|c| *c == u16::from(b'\\')) {
+            Some(separator) => {
+                self.domain = Some(OsString::from_wide(&name[..separator]));
+                self.user = OsString::from_wide(&name[separator + 1..]);
+            }
+            None => self.user = OsString::from_wide(&name),
+        }
+        self
+    }
+    ///The domain of the [Sudo::user].  By default, the local account database is used.
+    ///
+    /// Only Windows has domains, so this isn't available elsewhere.
+    pub fn domain<S: AsRef<OsStr>>(&mut self, name: S) -> &mut Sudo {
+        self.domain = Some(name.as_ref().to_owned());
+        self
+    }
+    ///Matches `sudo -g` on Unix, but Windows has no primary group to choose, so if this is set, [Sudo::status] fails
+    /// with [std::io::ErrorKind::Unsupported] rather than run the program without it.
+    pub fn group<S: AsRef<OsStr>>(&mut self, name: S) -> &mut Sudo {
+        self.group = Some(name.as_ref().to_owned());
+        self
+    }
+    ///Matches the Unix method, but the program is started by the secondary logon service, which we don't hand our pipes
+    /// to yet.  If this is set, [Sudo::status] fails with [std::io::ErrorKind::Unsupported] rather than run the program
+    /// without its input.
+    pub fn stdin_bytes(&mut self, bytes: Vec<u8>) -> &mut Self {
+        self.input = Some(Input::Bytes(bytes));
+        self
+    }
+    ///Matches the Unix method, but like [Sudo::stdin_bytes], the next launch fails with [std::io::ErrorKind::Unsupported],
+    /// and writes fail with [std::io::ErrorKind::BrokenPipe].
+    pub fn stdin_stream(&mut self) -> StdinWriter {
+        let (writer, input) = StdinWriter::new();
+        self.input = Some(input);
+        writer
+    }
     pub fn arg<S: AsRef<OsStr>>(&mut self, arg: S) -> &mut Sudo {
         let barg = arg.as_ref();
         //' ' + '"' + arg + '"' is 3 overhead
@@ -62,6 +130,12 @@ impl Sudo {
                                                              CREATE_PROCESS_LOGON_FLAGS,CREATE_UNICODE_ENVIRONMENT};
         use winbindings::Windows::Win32::Foundation::{PWSTR,HANDLE};
         use winbindings::Windows::Win32::System::Diagnostics::Debug::GetLastError;
+        if self.group.is_some() {
+            return Err(unsupported("running as a group"))
+        }
+        if Input::for_launch(&mut self.input).is_some() {
+            return Err(unsupported("writing to stdin"))
+        }
         //https://docs.microsoft.com/en-us/windows/win32/api/processthreadsapi/ns-processthreadsapi-startupinfoa
         //in that doc, the example mostly uses 0 for fields, with the exception of cb
         let startup_information = STARTUPINFOW {
@@ -93,10 +167,19 @@ impl Sudo {
         /*     If this parameter is NULL and the environment block of the parent process contains Unicode characters,
            you must also ensure that dwCreationFlags includes CREATE_UNICODE_ENVIRONMENT.*/
         let creation_flags = priority.as_priority_class() | CREATE_UNICODE_ENVIRONMENT;
+        let mut user = wide(&self.user);
+        //null means the local account database
+        let mut domain: Option<Vec<u16>> = self.domain.as_deref().map(wide);
+        let domain_arg = match &mut domain {
+            Some(domain) => PWSTR(domain.as_mut_ptr()),
+            None => PWSTR(std::ptr::null_mut()),
+        };
+        //a zeroizing wide copy, rather than letting the bindings convert a String
+        let mut password = self.password.to_wide();
         let r = unsafe {
-            CreateProcessWithLogonW("Administrator",
-                                                  PWSTR(std::ptr::null_mut()), //domain?
-                                                  self.password.clone(),
+            CreateProcessWithLogonW(PWSTR(user.as_mut_ptr()),
+                                                  domain_arg,
+                                                  PWSTR(password.as_mut_ptr()),
                                                   CREATE_PROCESS_LOGON_FLAGS(0), //profile not required
                                                   self.program.clone(),
                                                   self.command_line.clone(),
@@ -116,6 +199,14 @@ impl Sudo {
         let r = s.await;
         Ok(ExitStatus::from_raw(r))
     }
+    ///Runs the program, capturing its stdout and stderr, like on Unix.
+    ///
+    /// The program is started by the secondary logon service, which we don't hand our pipes to yet, so for now this
+    /// fails with [std::io::ErrorKind::Unsupported] without running the program.
+    #[cfg(feature="output")]
+    pub async fn output(&mut self, _priority: Priority) -> Result<crate::Output, Error> {
+        Err(unsupported("capturing output"))
+    }
 }
 
 #[test] fn test_sudo() {
@@ -126,4 +217,22 @@ impl Sudo {
         Err(Error::WinError(winbindings::Windows::Win32::System::Diagnostics::Debug::ERROR_LOGON_FAILURE)) => {},
         other => panic!("Unexpected result {:?}",other),
     }
-}
\ No newline at end of file
+}
+
+#[test] fn group_unsupported() {
+    let mut c = Sudo::new("whoami","invalid".to_string());
+    c.group("Administrators");
+    match kiruna::test::test_await(c.status(kiruna::Priority::Testing), std::time::Duration::from_secs(10)) {
+        Err(Error::IOError(e)) => assert_eq!(e.kind(), std::io::ErrorKind::Unsupported),
+        other => panic!("Unexpected result {:?}",other),
+    }
+}
+
+#[test] fn stdin_unsupported() {
+    let mut c = Sudo::new("whoami","invalid".to_string());
+    c.stdin_bytes(b"input".to_vec());
+    match kiruna::test::test_await(c.status(kiruna::Priority::Testing), std::time::Duration::from_secs(10)) {
+        Err(Error::IOError(e)) => assert_eq!(e.kind(), std::io::ErrorKind::Unsupported),
+        other => panic!("Unexpected result {:?}",other),
+    }
+}
@@ -0,0 +1,128 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Condvar, Mutex, MutexGuard};
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
+use once_cell::sync::Lazy;
+
+///Deadlines that somebody is waiting on.
+#[derive(Debug)]
+struct Timers {
+    next_id: u64,
+    ///Registered deadlines, and how to wake whoever cares about them
+    pending: HashMap<u64,(Instant,Waker)>,
+    ///whether a timer thread is running
+    timer_thread: bool,
+}
+
+static TIMERS: Lazy<(Mutex<Timers>,Condvar)> = Lazy::new(|| {
+    (Mutex::new(Timers { next_id: 0, pending: HashMap::new(), timer_thread: false }), Condvar::new())
+});
+
+fn timers() -> MutexGuard<'static, Timers> {
+    TIMERS.0.lock().unwrap()
+}
+
+///Sleeps until the earliest deadline, wakes everyone who is due, and stops when nothing is pending.
+fn timer_thread() {
+    let mut lock = timers();
+    loop {
+        let now = Instant::now();
+        lock.pending.retain(|_, (deadline, waker)| {
+            if *deadline <= now {
+                waker.wake_by_ref();
+                false
+            }
+            else {
+                true
+            }
+        });
+        let earliest = match lock.pending.values().map(|(deadline,_)| *deadline).min() {
+            Some(earliest) => earliest,
+            None => {
+                lock.timer_thread = false;
+                return;
+            }
+        };
+        lock = TIMERS.1.wait_timeout(lock, earliest - now).unwrap().0;
+    }
+}
+
+///A future that resolves once the deadline has passed.
+///
+/// Like waitpid, this uses a dedicated thread, which is launched on demand and stops when there is nothing to wait for.
+#[derive(Debug)]
+pub(crate) struct Delay {
+    deadline: Instant,
+    id: Option<u64>,
+}
+impl Delay {
+    pub(crate) fn new(duration: Duration) -> Delay {
+        Delay { deadline: Instant::now() + duration, id: None }
+    }
+}
+impl Future for Delay {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut lock = timers();
+        if Instant::now() >= self.deadline {
+            if let Some(id) = self.id.take() {
+                lock.pending.remove(&id);
+            }
+            return Poll::Ready(())
+        }
+        let id = match self.id {
+            Some(id) => id,
+            None => {
+                let id = lock.next_id;
+                lock.next_id += 1;
+                self.id = Some(id);
+                id
+            }
+        };
+        lock.pending.insert(id, (self.deadline, cx.waker().clone()));
+        if lock.timer_thread {
+            //the deadline may be sooner than the one the thread is sleeping towards
+            TIMERS.1.notify_one();
+        }
+        else {
+            lock.timer_thread = true;
+            std::thread::spawn(timer_thread);
+        }
+        Poll::Pending
+    }
+}
+impl Drop for Delay {
+    fn drop(&mut self) {
+        if let Some(id) = self.id {
+            timers().pending.remove(&id);
+        }
+    }
+}
+
+///Resolves to the future's output, or to `None` if the duration passes first.
+///
+/// The future is borrowed rather than consumed, so that the caller can keep driving it after the time is up.
+#[derive(Debug)]
+pub(crate) struct Timeout<F> {
+    future: F,
+    delay: Delay,
+}
+pub(crate) fn timeout<F: Future + Unpin>(future: F, duration: Duration) -> Timeout<F> {
+    Timeout { future, delay: Delay::new(duration) }
+}
+impl<F: Future + Unpin> Future for Timeout<F> {
+    type Output = Option<F::Output>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if let Poll::Ready(output) = Pin::new(&mut self.future).poll(cx) {
+            return Poll::Ready(Some(output))
+        }
+        match Pin::new(&mut self.delay).poll(cx) {
+            Poll::Ready(()) => Poll::Ready(None),
+            Poll::Pending => Poll::Pending
+        }
+    }
+}
@@ -1,11 +1,51 @@
+use std::process::ExitStatus;
+
 #[cfg(target_os="macos")]
 mod macos;
 #[cfg(target_os="macos")]
 pub (crate) use macos::ProcessFuture;
+#[cfg(target_os="linux")]
+mod linux;
+#[cfg(target_os="linux")]
+pub (crate) use linux::{ProcessFuture, Fd};
 #[cfg(target_os="windows")]
 mod windows;
 #[cfg(target_os="windows")]
 pub (crate) use windows::ProcessFuture;
 
+///The pid type [ProcessFuture] expects
+#[cfg(any(target_os="macos",target_os="linux"))]
+pub (crate) type Pid = i32;
+#[cfg(target_os="windows")]
+pub (crate) type Pid = u32;
+
+///Converts the output of [ProcessFuture] into an [ExitStatus].
+pub (crate) fn exit_status(raw: <ProcessFuture as std::future::Future>::Output) -> ExitStatus {
+    #[cfg(any(target_os="macos",target_os="linux"))]
+    use std::os::unix::process::ExitStatusExt;
+    #[cfg(target_os="windows")]
+    use std::os::windows::process::ExitStatusExt;
+    ExitStatus::from_raw(raw)
+}
+
 #[cfg(all(target_os="macos",test))]
 pub (crate) use macos::test;
+#[cfg(all(target_os="linux",test))]
+pub (crate) use linux::test;
+
+///Children that were not handed to us must be left for their owner to reap.
+#[cfg(all(test, any(target_os="macos", target_os="linux")))]
+#[allow(clippy::zombie_processes)]
+#[test] fn foreign_child_wait() {
+    use std::process::Command;
+    use std::task::Poll;
+    let _guard = test::TEST_SEMAPHORE.lock();
+    let ours = Command::new("sleep").arg("0.5").spawn().unwrap();
+    let mut future = ProcessFuture::new(ours.id() as i32);
+    //start the waiting thread
+    assert_eq!(kiruna::test::test_poll(&mut future), Poll::Pending);
+    let mut foreign = Command::new("sleep").arg("0.1").spawn().unwrap();
+    let status = foreign.wait().expect("foreign child was reaped out from under its owner");
+    assert!(status.success());
+    assert_eq!(kiruna::test::test_await(future, std::time::Duration::from_secs(2)), 0);
+}
@@ -0,0 +1,331 @@
+use std::sync::{Mutex, MutexGuard};
+use once_cell::sync::Lazy;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll, Waker};
+use std::collections::{HashMap};
+
+///Waits for the given pid
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+pub (crate) struct ProcessFuture(i32);
+
+impl ProcessFuture {
+    pub fn new(pid: i32) -> ProcessFuture {
+        ProcessFuture(pid)
+    }
+    ///Sends the signal to the process, unless it has already been reaped.
+    ///
+    /// Don't call this once the future has resolved, since the pid may then belong to somebody else.
+    pub fn signal(&self, signal: i32) -> Result<(), std::io::Error> {
+        let s = Waitpid::shared();
+        if s.outbox.contains_key(self) {
+            return Ok(())
+        }
+        let r = match s.inbox.get(self) {
+            //the pidfd refers to this very process, even if the waiting thread has just reaped it
+            Some(waiting) => unsafe{ libc::syscall(libc::SYS_pidfd_send_signal, waiting.pidfd.0, signal, std::ptr::null::<libc::siginfo_t>(), 0) as i32 },
+            //otherwise, the process is only reaped while holding the lock, so the pid is still ours
+            None => unsafe{ libc::kill(self.0, signal) },
+        };
+        if r != 0 {
+            let error = std::io::Error::last_os_error();
+            //already exited
+            if error.raw_os_error() == Some(libc::ESRCH) { return Ok(()) }
+            return Err(error)
+        }
+        Ok(())
+    }
+}
+
+///Owns a file descriptor, closing it on drop.
+#[derive(Debug)]
+pub(crate) struct Fd(pub(crate) i32);
+impl Drop for Fd {
+    fn drop(&mut self) {
+        unsafe{ libc::close(self.0) };
+    }
+}
+
+impl Fd {
+    ///Opens a pidfd for the pid.
+    ///
+    /// Because the pid is our own child and we have not reaped it yet, the pid cannot have been reused.
+    /// This fails on kernels before 5.3, under some seccomp policies, or when we're out of descriptors.
+    fn pidfd(pid: i32) -> Result<Fd, std::io::Error> {
+        let fd = unsafe{ libc::syscall(libc::SYS_pidfd_open, pid, 0) };
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error())
+        }
+        Ok(Fd(fd as i32))
+    }
+    pub(crate) fn eventfd() -> Result<Fd, std::io::Error> {
+        let fd = unsafe{ libc::eventfd(0, libc::EFD_CLOEXEC)};
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error())
+        }
+        Ok(Fd(fd))
+    }
+}
+
+///Waits for the process on a dedicated thread, for when we can't get a pidfd for it.
+///
+/// The thread only waits for the exit, and leaves the process a zombie until it holds the lock, so that the pid
+/// stays ours for as long as the process is in [Waitpid::blocking].
+fn wait_blocking(future: ProcessFuture) {
+    std::thread::spawn(move || {
+        let mut info: libc::siginfo_t = unsafe{ std::mem::zeroed() };
+        loop {
+            let r = unsafe{ libc::waitid(libc::P_PID, future.0 as libc::id_t, &mut info, libc::WEXITED | libc::WNOWAIT)};
+            if r == 0 { break }
+            let error = std::io::Error::last_os_error();
+            assert_eq!(error.kind(), std::io::ErrorKind::Interrupted, "waitid failed for {:?}: {:?}", future, error);
+        }
+        let mut s = Waitpid::shared();
+        //the process has exited, so this returns right away
+        let r = unsafe{ libc::waitid(libc::P_PID, future.0 as libc::id_t, &mut info, libc::WEXITED)};
+        assert!(r == 0, "waitid failed for {:?}: {:?}", future, std::io::Error::last_os_error());
+        s.outbox.insert(future.clone(), raw_status(&info));
+        if let Some(waker) = s.blocking.remove(&future) {
+            waker.wake();
+        }
+    });
+}
+
+///A process we have been asked to await.
+#[derive(Debug)]
+struct Waiting {
+    waker: Waker,
+    pidfd: Fd,
+}
+
+///A dedicated loop to watch for child process events.
+///
+/// Unlike a `waitpid(-1)` design, each process is watched through its own pidfd, so we only reap
+/// children that were handed to us.  Other children (e.g. from [std::process::Command]) are left alone.
+#[derive(Debug)]
+struct Waitpid {
+    ///Processes we have been asked to await.  We know how to wake them.
+    inbox: HashMap<ProcessFuture,Waiting>,
+    ///Processes that we know have terminated, but nobody polled us about them
+    outbox: HashMap<ProcessFuture,i32>,
+    ///Processes without a pidfd, each awaited by its own thread (see [wait_blocking])
+    blocking: HashMap<ProcessFuture,Waker>,
+    ///Signaled to wake the waiting thread so that it picks up new pidfds.  Without it, every process is awaited with [wait_blocking].
+    wakeup: Option<Fd>,
+    ///whether a waiting thread is running
+    waiting_thread: bool
+}
+
+///Converts `waitid` info into the status format of `waitpid`, which is what [std::os::unix::process::ExitStatusExt::from_raw] expects.
+fn raw_status(info: &libc::siginfo_t) -> i32 {
+    let status = unsafe{ info.si_status() };
+    match info.si_code {
+        libc::CLD_EXITED => (status & 0xff) << 8,
+        libc::CLD_KILLED => status & 0x7f,
+        libc::CLD_DUMPED => (status & 0x7f) | 0x80,
+        other => unreachable!("Unexpected si_code {:?} for WEXITED", other)
+    }
+}
+
+impl Waitpid {
+    fn shared() -> MutexGuard<'static, Waitpid> {
+        static SHARED_WAIT: Lazy<Mutex<Waitpid>> = Lazy::new(||Mutex::new(Waitpid {
+            inbox: Default::default(),
+            outbox: Default::default(),
+            blocking: Default::default(),
+            wakeup: Fd::eventfd().ok(),
+            waiting_thread: false
+        }));
+        SHARED_WAIT.lock().unwrap()
+    }
+    ///Err indicates we need to stop the thread.  Note that for correctness, waiting_thread is assigned to false
+    ///internally, on the same lock that this function acquires.
+    fn wait_some(wakeup: i32) -> Result<(),()> {
+        //build the list of descriptors to watch.  Only this thread removes from the inbox, so the fds
+        //stay open while we wait on them outside the lock.
+        let (mut pollfds, futures) = {
+            let mut s = Waitpid::shared();
+            if s.inbox.is_empty() {
+                s.waiting_thread = false;
+                return Err(());
+            }
+            let mut pollfds = Vec::with_capacity(s.inbox.len() + 1);
+            let mut futures = Vec::with_capacity(s.inbox.len());
+            pollfds.push(libc::pollfd { fd: wakeup, events: libc::POLLIN, revents: 0});
+            for (future, waiting) in &s.inbox {
+                pollfds.push(libc::pollfd { fd: waiting.pidfd.0, events: libc::POLLIN, revents: 0});
+                futures.push(future.clone());
+            }
+            (pollfds, futures)
+        };
+        let r = unsafe{ libc::poll(pollfds.as_mut_ptr(), pollfds.len() as libc::nfds_t, -1)};
+        if r < 0 {
+            let error = std::io::Error::last_os_error();
+            assert_eq!(error.kind(), std::io::ErrorKind::Interrupted, "poll failed: {:?}", error);
+            return Ok(());
+        }
+        if pollfds[0].revents != 0 {
+            //new processes are in the inbox; drain the counter, and rebuild on the next go-around
+            let mut counter: u64 = 0;
+            unsafe{ libc::read(pollfds[0].fd, &mut counter as *mut u64 as *mut libc::c_void, std::mem::size_of::<u64>())};
+        }
+        let mut exited = Vec::new();
+        for (pollfd, future) in pollfds[1..].iter().zip(futures) {
+            if pollfd.revents == 0 { continue; }
+            let mut info: libc::siginfo_t = unsafe{ std::mem::zeroed() };
+            let r = unsafe{ libc::waitid(libc::P_PIDFD, pollfd.fd as libc::id_t, &mut info, libc::WEXITED)};
+            assert!(r == 0, "waitid failed for {:?}: {:?}", future, std::io::Error::last_os_error());
+            exited.push((future, raw_status(&info)));
+        }
+        //at this point (after our wait), we need to lock and report these events
+        let mut s = Waitpid::shared();
+        for (future, status) in exited {
+            //removing closes the pidfd
+            let waiting = s.inbox.remove(&future).unwrap();
+            s.outbox.insert(future, status);
+            waiting.waker.wake();
+        }
+        if s.inbox.is_empty() {
+            s.waiting_thread = false;
+            Err(())
+        }
+        else {
+            Ok(())
+        }
+    }
+    ///Poll, inside the lock
+    fn poll_inside(&mut self, future: ProcessFuture, waker: Waker) -> Poll<i32> {
+        if let Some(status) = self.outbox.remove(&future){
+            return Poll::Ready(status)
+        }
+        if let Some(waiting) = self.inbox.get_mut(&future) {
+            //update with new waker
+            waiting.waker = waker;
+            return Poll::Pending
+        }
+        if let Some(old) = self.blocking.get_mut(&future) {
+            *old = waker;
+            return Poll::Pending
+        }
+        let (wakeup, pidfd) = match (&self.wakeup, Fd::pidfd(future.0)) {
+            (Some(wakeup), Ok(pidfd)) => (wakeup.0, pidfd),
+            _ => {
+                self.blocking.insert(future.clone(), waker);
+                wait_blocking(future);
+                return Poll::Pending
+            }
+        };
+        self.inbox.insert(future, Waiting { waker, pidfd });
+        if self.waiting_thread {
+            //tell the running thread about the new pidfd
+            let one: u64 = 1;
+            let r = unsafe{ libc::write(wakeup, &one as *const u64 as *const libc::c_void, std::mem::size_of::<u64>())};
+            assert!(r >= 0, "eventfd write failed: {:?}", std::io::Error::last_os_error());
+        }
+        else {
+            self.waiting_thread = true;
+            std::thread::spawn(move || {
+                while Waitpid::wait_some(wakeup).is_ok() {
+                    //loop
+                }
+                //wait_some will unset waiting_thread already
+            });
+        }
+        //try again later
+        Poll::Pending
+    }
+    fn poll(future: ProcessFuture, waker: Waker) -> Poll::<i32> {
+        Self::shared().poll_inside(future, waker)
+    }
+}
+
+impl Future for ProcessFuture {
+    type Output = i32;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Waitpid::poll(self.clone(), cx.waker().clone())
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use std::sync::{Mutex};
+    use once_cell::sync::Lazy;
+
+    //Generally, we want our tests to run only one at a time
+    pub static TEST_SEMAPHORE: Lazy<Mutex<()>> = Lazy::new(|| {
+        Mutex::new(())
+    });
+}
+
+//children are reaped by ProcessFuture rather than std
+#[allow(clippy::zombie_processes)]
+#[test] fn toy_await_1() {
+    use std::process::Command;
+    use kiruna::test::test_await;
+    let _guard = test::TEST_SEMAPHORE.lock();
+    let mut command = Command::new("sleep");
+    command.arg("0.1");
+    let child = command.spawn().unwrap();
+    let future = ProcessFuture::new(child.id() as i32);
+    let status = test_await(future, std::time::Duration::from_secs(1));
+    assert_eq!(status, 0);
+    if Waitpid::shared().waiting_thread {
+        panic!("Failed to shut down waitpid??");
+    }
+}
+
+#[allow(clippy::zombie_processes)]
+#[test] fn toy_await_2() {
+    use std::process::Command;
+    use kiruna::test::test_poll;
+    let _guard = test::TEST_SEMAPHORE.lock();
+    use std::time::Duration;
+    fn spawn(duration: Duration) -> std::process::Child {
+        let mut command = Command::new("sleep");
+        command.arg(duration.as_secs_f32().to_string());
+        command.spawn().unwrap()
+    }
+    let f1 =  ProcessFuture::new(spawn(Duration::from_millis(100)).id() as i32);
+    let f2 =  ProcessFuture::new(spawn(Duration::from_millis(200)).id() as i32);
+    let f3 =  ProcessFuture::new(spawn(Duration::from_millis(300)).id() as i32);
+    let f4 =  ProcessFuture::new(spawn(Duration::from_millis(400)).id() as i32);
+    let f5 =  ProcessFuture::new(spawn(Duration::from_millis(500)).id() as i32);
+    let mut poll_me = vec![f1,f2,f3,f4,f5];
+    let clock = std::time::Instant::now();
+    while clock.elapsed().as_secs() < 2 && !poll_me.is_empty() {
+        poll_me.retain(|f| test_poll(f.clone()) == Poll::Pending);
+    }
+    assert!(poll_me.is_empty(), "Processes did not finish: {:?}", poll_me);
+    if Waitpid::shared().waiting_thread {
+        panic!("Failed to shut down waitpid??");
+    }
+}
+
+#[allow(clippy::zombie_processes)]
+#[test] fn toy_await_signal() {
+    use std::process::Command;
+    use kiruna::test::test_await;
+    use std::os::unix::process::ExitStatusExt;
+    let _guard = test::TEST_SEMAPHORE.lock();
+    let mut command = Command::new("sleep");
+    command.arg("10");
+    let child = command.spawn().unwrap();
+    unsafe{ libc::kill(child.id() as i32, libc::SIGKILL) };
+    let future = ProcessFuture::new(child.id() as i32);
+    let status = std::process::ExitStatus::from_raw(test_await(future, std::time::Duration::from_secs(1)));
+    assert_eq!(status.signal(), Some(libc::SIGKILL));
+}
+
+#[allow(clippy::zombie_processes)]
+#[test] fn toy_await_blocking() {
+    use std::process::Command;
+    use kiruna::test::test_await;
+    let _guard = test::TEST_SEMAPHORE.lock();
+    let child = Command::new("sleep").arg("0.1").spawn().unwrap();
+    let future = ProcessFuture::new(child.id() as i32);
+    //as if pidfd_open had failed
+    Waitpid::shared().blocking.insert(future.clone(), Waker::noop().clone());
+    wait_blocking(future.clone());
+    assert_eq!(test_await(future, std::time::Duration::from_secs(1)), 0);
+}
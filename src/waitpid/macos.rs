@@ -13,61 +13,120 @@ impl ProcessFuture {
     pub fn new(pid: i32) -> ProcessFuture {
         ProcessFuture(pid)
     }
+    ///Sends the signal to the process, unless it has already been reaped.
+    ///
+    /// Processes are only reaped while holding the lock, so until the future resolves, the pid is still ours.
+    /// Don't call this afterwards.
+    pub fn signal(&self, signal: i32) -> Result<(), std::io::Error> {
+        let s = Waitpid::shared();
+        if s.outbox.contains_key(self) {
+            return Ok(())
+        }
+        if unsafe{ libc::kill(self.0, signal) } != 0 {
+            return Err(std::io::Error::last_os_error())
+        }
+        Ok(())
+    }
+}
+
+///Owns the kqueue that watches our processes.
+#[derive(Debug)]
+struct KQueue(i32);
+impl KQueue {
+    fn new() -> KQueue {
+        let fd = unsafe{ libc::kqueue() };
+        assert!(fd >= 0, "kqueue failed: {:?}", std::io::Error::last_os_error());
+        KQueue(fd)
+    }
+    ///Asks the kqueue to report when the process exits.
+    ///
+    /// Err indicates the process could not be found, which usually means it already exited.
+    fn watch(&self, pid: i32) -> Result<(),std::io::Error> {
+        let change = libc::kevent {
+            ident: pid as libc::uintptr_t,
+            filter: libc::EVFILT_PROC,
+            flags: libc::EV_ADD | libc::EV_ONESHOT,
+            fflags: libc::NOTE_EXIT,
+            data: 0,
+            udata: std::ptr::null_mut()
+        };
+        let r = unsafe{ libc::kevent(self.0, &change, 1, std::ptr::null_mut(), 0, std::ptr::null())};
+        if r < 0 { Err(std::io::Error::last_os_error()) } else { Ok(()) }
+    }
+}
+impl Drop for KQueue {
+    fn drop(&mut self) {
+        unsafe{ libc::close(self.0) };
+    }
+}
+
+///Reaps exactly the pid given.  Only call this once the process has exited.
+fn reap(pid: i32) -> i32 {
+    let mut status: i32 = 0;
+    loop {
+        let r = unsafe{ waitpid(pid, &mut status, 0)};
+        if r == pid { return status }
+        let error = std::io::Error::last_os_error();
+        assert_eq!(error.kind(), std::io::ErrorKind::Interrupted, "waitpid failed for {:?}: {:?}", pid, error);
+    }
 }
 
 ///A dedicated loop to watch for child process events.
+///
+/// Each process is watched individually (with kqueue's `EVFILT_PROC`), and reaped with `waitpid` on its own pid,
+/// so we only reap children that were handed to us.  Other children (e.g. from [std::process::Command]) are left alone.
 #[derive(Debug)]
 struct Waitpid {
     ///Processes we have been asked to await.  We know how to wake them.
     inbox: HashMap<ProcessFuture,Waker>,
     ///Processes that we know have terminated, but nobody polled us about them
     outbox: HashMap<ProcessFuture,i32>,
+    ///Reports process exits to the waiting thread.
+    kqueue: KQueue,
     ///waiting thread, if any.  Note that we may need orphan values without any running thread.
     waiting_thread: bool
 }
 impl Waitpid {
-    ///Err indicates we need to stop the thread.  Note that for correctness, waiting_thread is assigned to None
+    ///Err indicates we need to stop the thread.  Note that for correctness, waiting_thread is assigned to false
     ///internally, on the same lock that this function acquires.
-    fn wait_one() -> Result<(),()> {
-        let mut status: i32 = 0;
-        let waited_pid = unsafe{ waitpid(-1, &mut status, 0)};
-        //println!("waited_pid {:?} status {:?}",waited_pid,status);
-        if waited_pid < 0 {
-            //println!("error {:?}",unsafe{*libc::__error()});
-            //usually, we have no child processes.
-            let mut s = Waitpid::shared();
-            //Because we aren't holding a lock, it's possible
-            //that Waitpid was removed while this thread was still active.
-            //If so, handle this gracefully
-            s.waiting_thread = false;
-            return Err(());
+    fn wait_some(kqueue: i32) -> Result<(),()> {
+        const EVENTS: usize = 16;
+        let mut events: [libc::kevent; EVENTS] = unsafe{ std::mem::zeroed() };
+        let r = unsafe{ libc::kevent(kqueue, std::ptr::null(), 0, events.as_mut_ptr(), EVENTS as i32, std::ptr::null())};
+        if r < 0 {
+            let error = std::io::Error::last_os_error();
+            assert_eq!(error.kind(), std::io::ErrorKind::Interrupted, "kevent failed: {:?}", error);
+            return Ok(());
         }
-        let found_future = ProcessFuture(waited_pid);
-        //at this point (after our wait), we need to lock and report this event
+        //at this point (after our wait), we need to lock and report these events.  We reap inside the lock, so that
+        //signals sent under it can't reach a process that reused the pid.
         let mut s = Waitpid::shared();
-        //insert the outbox
-        s.outbox.insert(found_future.clone(), status);
-        //If we know about this, wake the appropriate party
-        //If not, wait for somebody to tell us how to do this in the future
-        if let Some(waker) = s.inbox.remove(&found_future) {
-            // println!("wake_by_ref {:?}",found_future);
-            waker.wake_by_ref();
+        for event in &events[..r as usize] {
+            if event.filter != libc::EVFILT_PROC || event.fflags & libc::NOTE_EXIT == 0 { continue }
+            let pid = event.ident as i32;
+            let found_future = ProcessFuture(pid);
+            //the process has exited, so this returns promptly
+            let status = reap(pid);
+            //If we know about this, wake the appropriate party
+            if let Some(waker) = s.inbox.remove(&found_future) {
+                waker.wake();
+            }
+            s.outbox.insert(found_future, status);
         }
-        if s.inbox.len() == 0 {
-            //println!("inbox zero");
+        if s.inbox.is_empty() {
             s.waiting_thread = false;
             Err(())
         }
         else {
             Ok(())
         }
-
     }
     //todo: This could be promoted to option to save some memory.  Currently, this static leaks ~150 bytes.
     fn shared() -> MutexGuard<'static, Waitpid> {
         static SHARED_WAIT: Lazy<Mutex<Waitpid>> = Lazy::new(||Mutex::new(Waitpid {
             inbox: Default::default(),
             outbox: Default::default(),
+            kqueue: KQueue::new(),
             waiting_thread: false
         }));
         SHARED_WAIT.lock().unwrap()
@@ -78,19 +137,26 @@ impl Waitpid {
         if let Some(status) = self.outbox.remove(&future){
             return Poll::Ready(status)
         }
-        //update with new waker
+        if let Some(old) = self.inbox.get_mut(&future) {
+            //update with new waker
+            *old = waker;
+            return Poll::Pending
+        }
+        if let Err(e) = self.kqueue.watch(future.0) {
+            assert_eq!(e.raw_os_error(), Some(libc::ESRCH), "Can't watch {:?}: {:?}", future, e);
+            //The process is already on its way out (kqueue won't attach to a zombie), so this returns promptly.
+            return Poll::Ready(reap(future.0))
+        }
         self.inbox.insert(future, waker);
         //If we have no running thread, we need to start one
         if !self.waiting_thread {
             self.waiting_thread = true;
-            std::thread::spawn(|| {
-                //println!("waitpid thread begin");
-                //new lock
-                while Waitpid::wait_one().is_ok() {
+            let kqueue = self.kqueue.0;
+            std::thread::spawn(move || {
+                while Waitpid::wait_some(kqueue).is_ok() {
                     //loop
                 }
-                //println!("waitpid thread shutdown");
-                //wait_one will unset the thread handle already
+                //wait_some will unset waiting_thread already
             });
         }
         //try again later
@@ -98,10 +164,7 @@ impl Waitpid {
     }
     fn poll(future: ProcessFuture, waker: Waker) -> Poll::<i32> {
         let mut lock = Self::shared();
-        let poll_inside = lock.poll_inside(future, waker);
-        //println!("poll_inside {:?}",poll_inside);
-        poll_inside
-
+        lock.poll_inside(future, waker)
     }
 }
 
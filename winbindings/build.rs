@@ -10,6 +10,8 @@ fn main() {
             PROCESS_CREATION_FLAGS,
         },
         Windows::Win32::Foundation::CloseHandle,
+        Windows::Win32::Globalization::{GetOEMCP,MultiByteToWideChar},
+        Windows::Win32::System::Memory::{VirtualLock,VirtualUnlock},
         Windows::Win32::System::Diagnostics::Debug::{GetLastError,WIN32_ERROR},
 
     }